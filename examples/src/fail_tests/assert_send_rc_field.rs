@@ -0,0 +1,12 @@
+use ouroboros::self_referencing;
+use std::rc::Rc;
+
+#[self_referencing(assert_send)]
+struct SharedCounter {
+    counter: Rc<i32>,
+    text: String,
+    #[borrows(text)]
+    first_word: &'this str,
+}
+
+fn main() {}
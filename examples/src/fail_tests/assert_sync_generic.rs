@@ -0,0 +1,11 @@
+use ouroboros::self_referencing;
+
+#[self_referencing(assert_sync)]
+struct Items<T: 'static> {
+    items: Vec<T>,
+    #[borrows(items)]
+    #[covariant]
+    first: Option<&'this T>,
+}
+
+fn main() {}
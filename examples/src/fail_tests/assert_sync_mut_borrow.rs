@@ -0,0 +1,13 @@
+use core::cell::Cell;
+
+use ouroboros::self_referencing;
+
+// Mutably borrowing a field only gives a Sync struct if the field itself is Sync.
+#[self_referencing(pinned, assert_sync)]
+struct PinnedCounter {
+    counter: Cell<i32>,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut Cell<i32>,
+}
+
+fn main() {}
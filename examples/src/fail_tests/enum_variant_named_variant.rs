@@ -0,0 +1,14 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+enum Document {
+    Raw(String),
+    Variant {
+        src: String,
+        #[borrows(src)]
+        #[covariant]
+        words: Vec<&'this str>,
+    },
+}
+
+fn main() {}
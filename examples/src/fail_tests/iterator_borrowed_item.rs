@@ -0,0 +1,21 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct OwningIter {
+    names: Vec<String>,
+    #[borrows(names)]
+    #[covariant]
+    #[iterator]
+    remaining: std::slice::Iter<'this, String>,
+}
+
+#[self_referencing]
+struct OwningKeys {
+    ages: std::collections::HashMap<String, u32>,
+    #[borrows(ages)]
+    #[covariant]
+    #[iterator]
+    remaining: std::collections::hash_map::Keys<'this, String, u32>,
+}
+
+fn main() {}
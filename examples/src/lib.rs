@@ -8,6 +8,7 @@ use ouroboros::self_referencing;
 #[cfg(test)]
 mod ok_tests;
 
+#[allow(dead_code, clippy::borrowed_box)]
 pub struct Ext<'this, T, const REV: bool>(&'this Box<T>);
 
 #[self_referencing(pub_extras)]
@@ -26,6 +27,25 @@ pub struct DataAndRef {
     data_ref: &'this i32,
 }
 
+#[self_referencing]
+/// A tuple struct version of `DataAndRef`. Generated methods refer to fields by their index, like
+/// `borrow_1()`.
+pub struct TupleDataAndRef(i32, #[borrows(0)] &'this i32);
+
+#[self_referencing]
+/// An enum where only one variant is self-referencing. That variant is stored as a
+/// `DataOrRefBorrowing` struct, which has the same methods as `DataAndRef`.
+pub enum DataOrRef {
+    /// A variant without any borrows, which is stored as-is.
+    Plain(i32),
+    /// A self-referencing variant.
+    Borrowing {
+        data: i32,
+        #[borrows(data)]
+        data_ref: &'this i32,
+    },
+}
+
 #[self_referencing()]
 #[allow(clippy::redundant_allocation)]
 /// A chain of references, where c references b which references a.
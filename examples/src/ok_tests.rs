@@ -1,4 +1,6 @@
-use alloc::{borrow::ToOwned, boxed::Box};
+#![allow(clippy::multiple_bound_locations)]
+
+use alloc::{borrow::ToOwned, boxed::Box, format, rc::Rc, string::String, vec, vec::Vec};
 use core::fmt::Debug;
 
 use ouroboros::self_referencing;
@@ -52,7 +54,8 @@ struct AutoDetectCovarianceOnFieldsWithoutThis {
 
 /// This test just makes sure that the macro copes with a ton of template parameters being thrown at
 /// it, specifically checking that the templates work fine even when a generated struct doesn't need
-/// all of them. (E.G. heads will only contain 'd, A, and B.)
+/// all of them. (E.G. heads will only contain 'd, A, and B.) The bounds are deliberately repeated
+/// in the where clause.
 #[self_referencing]
 struct TemplateMess<'d, A, B: 'static, C: 'static>
 where
@@ -71,13 +74,412 @@ where
 
 // Regression test for broken derive macros.
 #[self_referencing]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
 struct DeriveCompilesOk<T: 'static> {
     data: T,
     #[borrows(data)]
     dref: &'this T,
 }
 
+// The generic parameter has the same name as the one Hash::hash() is usually written with.
+#[self_referencing]
+#[derive(PartialEq, Eq, Hash)]
+struct Keyed<H: core::hash::Hash + 'static> {
+    key: H,
+    #[borrows(key)]
+    key_ref: &'this H,
+}
+
+/// Records everything written to it, so that tests can compare what two values hash.
+#[derive(Default)]
+struct RecordingHasher(Vec<u8>);
+
+impl core::hash::Hasher for RecordingHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+fn first_word(text: &str) -> &str {
+    text.split(' ').next().unwrap()
+}
+
+#[self_referencing]
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct CloneWithBuilders {
+    text: String,
+    number: i32,
+    #[ouroboros(skip)]
+    #[clone_with(next_generation)]
+    generation: u32,
+    #[borrows(text)]
+    #[clone_with(first_word)]
+    first_word: &'this str,
+    #[borrows(number)]
+    number_ref: &'this i32,
+}
+
+fn next_generation(generation: &u32) -> u32 {
+    generation + 1
+}
+
+fn default_label(name: &str) -> &str {
+    if name.is_empty() {
+        "unnamed"
+    } else {
+        name
+    }
+}
+
+fn default_limit() -> u32 {
+    10
+}
+
+#[self_referencing]
+#[derive(Default)]
+struct DefaultConfig {
+    name: String,
+    values: Vec<i32>,
+    #[borrows(name)]
+    name_ref: &'this String,
+    #[borrows(mut values)]
+    values_ref: &'this mut Vec<i32>,
+    #[borrows(name)]
+    #[default_with(default_label)]
+    label: &'this str,
+    #[default_with(default_limit)]
+    limit: u32,
+}
+
+struct SplitConfig {
+    separator: char,
+    limit: usize,
+}
+
+#[self_referencing]
+struct ConsumedConfig {
+    config: SplitConfig,
+    text: String,
+    retries: u32,
+    #[borrows(text)]
+    first_char: &'this str,
+    #[borrows(text)]
+    #[consumes(config)]
+    #[covariant]
+    words: Vec<&'this str>,
+    #[borrows(text)]
+    #[consumes(retries)]
+    attempts: Vec<u32>,
+}
+
+#[self_referencing]
+struct LongestSplitWord {
+    text: String,
+    #[borrows(text)]
+    #[covariant]
+    words: Vec<&'this str>,
+    #[borrows(text)]
+    #[consumes(words)]
+    #[covariant]
+    longest: Option<&'this str>,
+}
+
+struct NotDebug;
+
+fn debug_len(data: &[u8], f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    write!(f, "{} bytes", data.len())
+}
+
+#[self_referencing]
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct SkippedFields {
+    #[debug(with = debug_len)]
+    buffer: Vec<u8>,
+    #[ouroboros(skip)]
+    handle: NotDebug,
+    #[debug(skip)]
+    counter: u32,
+    #[borrows(buffer)]
+    first: &'this u8,
+}
+
+fn split_words(text: &str) -> Vec<&str> {
+    text.split(' ').collect()
+}
+
+#[self_referencing]
+#[derive(Debug, Serialize, Deserialize)]
+struct SerializedWords {
+    text: String,
+    count: u32,
+    #[ouroboros(skip)]
+    lookups: u32,
+    #[borrows(text)]
+    #[covariant]
+    #[ouroboros(serialize)]
+    #[deserialize_with(split_words)]
+    words: Vec<&'this str>,
+}
+
+#[self_referencing(pinned)]
+struct PinnedFields {
+    text: String,
+    counter: i32,
+    #[borrows(text)]
+    first_word: &'this str,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut i32,
+}
+
+#[self_referencing(pinned)]
+struct PinnedTracked {
+    text: String,
+    tracker: Rc<()>,
+    #[borrows(text)]
+    #[not_covariant]
+    words: CheckedWords<'this>,
+    #[borrows(tracker)]
+    tracker_ref: &'this Rc<()>,
+}
+
+#[self_referencing(single_allocation)]
+struct SingleAllocationTracked {
+    text: String,
+    tracker: Rc<()>,
+    #[borrows(text)]
+    #[not_covariant]
+    words: CheckedWords<'this>,
+    #[borrows(tracker)]
+    tracker_ref: &'this Rc<()>,
+}
+
+#[self_referencing(single_allocation)]
+struct SingleAllocation {
+    text: String,
+    counter: i32,
+    label: String,
+    #[borrows(text)]
+    first_word: &'this str,
+    #[borrows(first_word)]
+    first_word_ref: &'this &'this str,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut i32,
+}
+
+// Compiles even though the mutably borrowed field keeps the struct from being Sync.
+#[self_referencing(pinned, assert_send)]
+struct PinnedCell {
+    cell: core::cell::Cell<i32>,
+    #[borrows(mut cell)]
+    cell_ref: &'this mut core::cell::Cell<i32>,
+}
+
+#[self_referencing(single_allocation, assert_send, assert_sync)]
+struct CounterInBlock {
+    counter: i32,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut i32,
+}
+
+#[self_referencing(assert_send, assert_sync)]
+struct SharedText {
+    text: String,
+    #[borrows(text)]
+    first_word: &'this str,
+}
+
+#[self_referencing(assert_send, assert_sync)]
+struct SendAndSync<T: Send + Sync + 'static> {
+    items: Vec<T>,
+    #[borrows(items)]
+    #[covariant]
+    first: Option<&'this T>,
+}
+
+fn numbers_tail(numbers: &[i32]) -> &[i32] {
+    &numbers[1..]
+}
+
+#[self_referencing]
+#[derive(Debug, Clone, PartialEq)]
+struct StableDerefFields {
+    #[stable_deref]
+    text: String,
+    #[stable_deref]
+    numbers: Vec<i32>,
+    #[stable_deref]
+    name: Rc<str>,
+    #[borrows(text)]
+    #[clone_with(first_word)]
+    first_word: &'this str,
+    #[borrows(numbers)]
+    #[clone_with(numbers_tail)]
+    numbers_tail: &'this [i32],
+    #[borrows(name)]
+    name_ref: &'this str,
+}
+
+#[self_referencing]
+struct StableDerefMut {
+    #[stable_deref]
+    counter: Box<i32>,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut i32,
+}
+
+#[self_referencing(stable_deref)]
+struct DetectedStableDeref {
+    text: String,
+    shared: Rc<i32>,
+    counter: Rc<i32>,
+    #[borrows(text, shared)]
+    first_word: (&'this str, &'this i32),
+    #[borrows(mut counter)]
+    counter_ref: &'this mut Rc<i32>,
+}
+
+#[self_referencing]
+struct ExtractableFields {
+    text: String,
+    #[borrows(text)]
+    #[extractable]
+    word_count: usize,
+    #[borrows(text)]
+    first_word: &'this str,
+    #[borrows(word_count)]
+    word_count_ref: &'this usize,
+    #[borrows(first_word)]
+    first_word_len: usize,
+}
+
+#[self_referencing]
+struct SplitText {
+    text: String,
+    #[borrows(text)]
+    #[covariant]
+    words: Vec<&'this str>,
+    #[borrows(text)]
+    first_word: &'this str,
+}
+
+#[self_referencing]
+struct LongestWord {
+    text: String,
+    #[borrows(text)]
+    longest: &'this str,
+}
+
+#[self_referencing]
+struct FirstAndLastWords {
+    text: String,
+    #[borrows(text)]
+    first_word: &'this str,
+    #[borrows(text)]
+    last_word: &'this str,
+    #[borrows(first_word, last_word)]
+    joined: String,
+}
+
+#[self_referencing]
+struct OwningNames {
+    names: Vec<String>,
+    #[borrows(names)]
+    #[covariant]
+    #[iterator]
+    remaining: core::iter::Cloned<core::slice::Iter<'this, String>>,
+}
+
+fn owning_names(names: Vec<String>) -> impl Iterator<Item = String> {
+    OwningNamesBuilder {
+        names,
+        remaining_builder: |names| names.iter().cloned(),
+    }
+    .build()
+}
+
+/// Counts the allocations it currently has live so tests can check boxes are freed with it.
+#[derive(Clone, Default)]
+struct CountingAllocator {
+    live: Rc<core::cell::Cell<usize>>,
+}
+
+unsafe impl allocator_api2::alloc::Allocator for CountingAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        self.live.set(self.live.get() + 1);
+        allocator_api2::alloc::Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
+        self.live.set(self.live.get() - 1);
+        allocator_api2::alloc::Global.deallocate(ptr, layout)
+    }
+}
+
+#[self_referencing(allocator = CountingAllocator)]
+#[derive(Debug)]
+struct CustomAllocated {
+    text: String,
+    counter: i32,
+    #[borrows(text)]
+    first_word: &'this str,
+    #[borrows(mut counter)]
+    counter_ref: &'this mut i32,
+}
+
+/// An allocator with a generic parameter, so that its type can contain `->`.
+#[derive(Clone)]
+struct TaggedAllocator<Tag>(core::marker::PhantomData<Tag>);
+
+unsafe impl<Tag> allocator_api2::alloc::Allocator for TaggedAllocator<Tag> {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        allocator_api2::alloc::Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
+        allocator_api2::alloc::Global.deallocate(ptr, layout)
+    }
+}
+
+// The options following the allocator must still be parsed as options.
+#[self_referencing(allocator = TaggedAllocator<fn() -> u8>, assert_send)]
+struct TaggedAllocated {
+    text: String,
+    #[borrows(text)]
+    first_word: &'this str,
+}
+
+#[self_referencing]
+#[derive(Debug, PartialEq, Eq)]
+struct TupleStruct(
+    String,
+    #[borrows(0)] &'this str,
+    #[borrows(1)] &'this &'this str,
+);
+
+#[self_referencing]
+#[derive(Debug, PartialEq, Eq)]
+enum Document {
+    Raw(String),
+    Parsed {
+        src: String,
+        #[borrows(src)]
+        #[covariant]
+        words: Vec<&'this str>,
+    },
+    Pair(i32, i32),
+    Empty,
+}
+
 // /// Regression test for #46
 // #[self_referencing]
 // struct PreviouslyBrokeAutoGeneratedChecker<T: 'static> {
@@ -164,6 +566,87 @@ async fn async_try_new_err() {
     }
 }
 
+// Miri crashes with Pin<Box<Future>> types due to
+// https://github.com/rust-lang/miri/issues/1038
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[tokio::test]
+async fn async_build_concurrent() {
+    let order = Rc::new(core::cell::RefCell::new(Vec::new()));
+    let (first_order, last_order, joined_order) = (order.clone(), order.clone(), order.clone());
+    let words = FirstAndLastWordsAsyncBuilder {
+        text: "concurrent builders run together".to_owned(),
+        first_word_builder: move |text| {
+            Box::pin(async move {
+                // Only finishes after the builder of last_word got to run.
+                tokio::task::yield_now().await;
+                first_order.borrow_mut().push("first_word");
+                text.split(' ').next().unwrap()
+            })
+        },
+        last_word_builder: move |text| {
+            Box::pin(async move {
+                last_order.borrow_mut().push("last_word");
+                text.rsplit(' ').next().unwrap()
+            })
+        },
+        joined_builder: move |first_word, last_word| {
+            Box::pin(async move {
+                joined_order.borrow_mut().push("joined");
+                format!("{} {}", first_word, last_word)
+            })
+        },
+    }
+    .build_concurrent()
+    .await;
+    assert_eq!(*words.borrow_first_word(), "concurrent");
+    assert_eq!(*words.borrow_last_word(), "together");
+    assert_eq!(words.borrow_joined(), "concurrent together");
+    assert_eq!(*order.borrow(), ["last_word", "first_word", "joined"]);
+}
+
+// Miri crashes with Pin<Box<Future>> types due to
+// https://github.com/rust-lang/miri/issues/1038
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[tokio::test]
+async fn async_build_cancellable() {
+    let mut recovered = None;
+    let building = BoxAndRefAsyncTryBuilder {
+        data: 12,
+        dref_builder: |_data| Box::pin(core::future::pending::<Result<_, ()>>()),
+    }
+    .build_cancellable(|heads| recovered = Some(heads.data));
+    tokio::select! {
+        biased;
+        _ = building => unreachable!(),
+        _ = async {} => {}
+    }
+    assert_eq!(recovered, Some(12));
+
+    let mut cancelled = false;
+    let result = BoxAndRefAsyncTryBuilder {
+        data: 34,
+        dref_builder: |_data| Box::pin(async move { Err(56u64) }),
+    }
+    .build_cancellable(|_heads| cancelled = true)
+    .await;
+    match result {
+        Err((56, heads)) => assert_eq!(heads.data, 34),
+        _ => panic!("Test failed."),
+    }
+    assert!(!cancelled);
+
+    let bar = BoxAndRefAsyncTryBuilder {
+        data: 78,
+        dref_builder: |data| Box::pin(async move { Result::<_, ()>::Ok(data) }),
+    }
+    .build_cancellable(|_heads| unreachable!())
+    .await;
+    match bar {
+        Ok(bar) => assert_eq!(*bar.borrow_dref(), &78),
+        Err(_) => panic!("Test failed."),
+    }
+}
+
 #[test]
 fn try_new() {
     let bar = BoxAndRefTryBuilder {
@@ -214,6 +697,916 @@ fn into_heads() {
     assert!(bar.into_heads().data == 12);
 }
 
+#[test]
+fn tuple_struct() {
+    let mut instance =
+        TupleStructBuilder("Hello world!".to_owned(), |text| &text[..5], |hello| hello).build();
+    assert_eq!(instance.borrow_0(), "Hello world!");
+    assert_eq!(*instance.borrow_1(), "Hello");
+    assert_eq!(**instance.borrow_2(), "Hello");
+    instance.with_2_mut(|field| *field = &"Bye");
+    instance.with(|fields| assert_eq!(**fields._2, "Bye"));
+    assert_eq!(
+        format!("{:?}", instance),
+        r#"TupleStruct("Hello world!", "Hello", "Bye")"#
+    );
+    let other = TupleStruct::new("Hello world!".to_owned(), |text| &text[..5], |hello| hello);
+    assert_ne!(instance, other);
+    assert_eq!(instance.into_heads()._0, "Hello world!");
+}
+
+#[test]
+fn derive_clone() {
+    let original = CloneWithBuilders::new(
+        "Hello world!".to_owned(),
+        42,
+        0,
+        |text| first_word(text),
+        |number| number,
+    );
+    let clone = original.clone();
+    assert_eq!(clone, original);
+    assert_eq!(*clone.borrow_generation(), 1);
+    assert_eq!(*clone.borrow_first_word(), "Hello");
+    assert!(core::ptr::eq(
+        *clone.borrow_number_ref(),
+        clone.borrow_number()
+    ));
+    assert!(!core::ptr::eq(
+        *clone.borrow_number_ref(),
+        original.borrow_number()
+    ));
+}
+
+#[test]
+fn derive_default() {
+    let mut config = DefaultConfig::default();
+    assert_eq!(*config.borrow_label(), "unnamed");
+    assert_eq!(*config.borrow_limit(), 10);
+    assert!(core::ptr::eq(
+        *config.borrow_name_ref(),
+        config.borrow_name()
+    ));
+    config.with_values_ref_mut(|values| values.push(1));
+    assert_eq!(config.into_heads().values, [1]);
+}
+
+#[test]
+fn iterator() {
+    let mut names = owning_names(vec![
+        "Ada".to_owned(),
+        "Grace".to_owned(),
+        "Alan".to_owned(),
+    ]);
+    assert_eq!(names.size_hint(), (3, Some(3)));
+    assert_eq!(names.next().as_deref(), Some("Ada"));
+    let rest: Vec<_> = names.collect();
+    assert_eq!(rest, ["Grace", "Alan"]);
+}
+
+#[test]
+fn consumes() {
+    let config = SplitConfig {
+        separator: ',',
+        limit: 2,
+    };
+    let consumed = ConsumedConfigBuilder {
+        config,
+        text: "a,b,c".to_owned(),
+        retries: 3,
+        first_char_builder: |text| &text[..1],
+        words_builder: |text, config| text.splitn(config.limit, config.separator).collect(),
+        attempts_builder: |_text, retries| (1..=retries).collect(),
+    }
+    .build();
+    assert_eq!(*consumed.borrow_first_char(), "a");
+    assert_eq!(*consumed.borrow_words(), ["a", "b,c"]);
+    assert_eq!(*consumed.borrow_attempts(), [1, 2, 3]);
+    let heads = consumed.into_heads();
+    assert_eq!(heads.text, "a,b,c");
+    let failed = ConsumedConfig::try_new_or_recover(
+        SplitConfig {
+            separator: ' ',
+            limit: 0,
+        },
+        "a b".to_owned(),
+        0,
+        |text| Ok(&text[..1]),
+        |_text, config| Err(config.limit),
+        |_text, _retries| Ok(Vec::new()),
+    );
+    match failed {
+        Err((limit, heads)) => {
+            assert_eq!(limit, 0);
+            assert_eq!(heads.text, "a b");
+        }
+        Ok(_) => panic!("The builder of words failed."),
+    }
+}
+
+#[test]
+fn consumes_built_field() {
+    let longest = LongestSplitWord::new(
+        "a longer sentence".to_owned(),
+        |text| text.split(' ').collect(),
+        |_text, words| words.into_iter().max_by_key(|word| word.len()),
+    );
+    assert_eq!(*longest.borrow_longest(), Some("sentence"));
+    let failed = LongestSplitWord::try_new_or_recover(
+        "a b".to_owned(),
+        |text| Ok(text.split(' ').collect()),
+        |_text, words| Err(words.len()),
+    );
+    match failed {
+        Err((count, heads)) => {
+            assert_eq!(count, 2);
+            assert_eq!(heads.text, "a b");
+        }
+        Ok(_) => panic!("The builder of longest failed."),
+    }
+}
+
+#[test]
+fn derive_hash_with_generic_named_h() {
+    use core::hash::Hash;
+    let hash = |value: &dyn Fn(&mut RecordingHasher)| {
+        let mut hasher = RecordingHasher::default();
+        value(&mut hasher);
+        hasher.0
+    };
+    let keyed = Keyed::new(7u32, |key| key);
+    assert_eq!(
+        hash(&|hasher| keyed.hash(hasher)),
+        hash(&|hasher| {
+            7u32.hash(hasher);
+            7u32.hash(hasher);
+        })
+    );
+}
+
+#[test]
+fn derive_hash_and_ord() {
+    let make = |number| DeriveCompilesOk::new(number, |number| number);
+    let mut set = alloc::collections::BTreeSet::new();
+    set.insert(make(3));
+    set.insert(make(1));
+    set.insert(make(3));
+    assert_eq!(
+        set.iter()
+            .map(|item| *item.borrow_data())
+            .collect::<Vec<_>>(),
+        [1, 3]
+    );
+    assert!(make(1) < make(2));
+    assert_eq!(
+        make(2).partial_cmp(&make(2)),
+        Some(core::cmp::Ordering::Equal)
+    );
+    let mut map = std::collections::HashMap::new();
+    map.insert(make(1), "one");
+    assert_eq!(map.get(&make(1)), Some(&"one"));
+    assert_eq!(map.get(&make(2)), None);
+}
+
+#[test]
+fn derive_skipped_fields() {
+    let make = |counter| SkippedFields::new(vec![1, 2, 3], NotDebug, counter, |buffer| &buffer[0]);
+    assert_eq!(
+        format!("{:?}", make(1)),
+        "SkippedFields { buffer: 3 bytes, first: 1 }"
+    );
+    assert_eq!(make(1), make(1));
+    assert_ne!(make(1), make(2));
+}
+
+#[test]
+fn serde_round_trip() {
+    let original = SerializedWords::new("Hello world!".to_owned(), 2, 7, |text| split_words(text));
+    let json = serde_json::to_string(&original).unwrap();
+    assert_eq!(
+        json,
+        r#"{"text":"Hello world!","count":2,"words":["Hello","world!"]}"#
+    );
+    let deserialized: SerializedWords = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized.borrow_words(), &["Hello", "world!"]);
+    assert_eq!(*deserialized.borrow_count(), 2);
+    assert_eq!(*deserialized.borrow_lookups(), 0);
+}
+
+#[test]
+fn pinned() {
+    let mut instance = PinnedFieldsBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 0,
+        first_word_builder: |text| first_word(text),
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+    assert!(core::ptr::eq(
+        *instance.borrow_first_word(),
+        &instance.borrow_text()[..5]
+    ));
+    instance
+        .as_mut()
+        .with_counter_ref_mut(|counter| **counter += 1);
+    instance
+        .as_mut()
+        .with_mut(|fields| **fields.counter_ref += 1);
+    instance.with(|fields| assert_eq!(**fields.counter_ref, 2));
+    let heads = instance.into_heads();
+    assert_eq!(heads.text, "Hello world!");
+    assert_eq!(heads.counter, 2);
+}
+
+#[test]
+fn pinned_in_place() {
+    let mut slot = Box::pin(core::mem::MaybeUninit::uninit());
+    let mut instance = PinnedFields::new_in_place(
+        slot.as_mut(),
+        "Hello world!".to_owned(),
+        41,
+        |text| first_word(text),
+        |counter| counter,
+    );
+    instance
+        .as_mut()
+        .with_counter_ref_mut(|counter| **counter += 1);
+    instance.with(|fields| assert_eq!(**fields.counter_ref, 42));
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+    unsafe { core::ptr::drop_in_place(core::pin::Pin::get_unchecked_mut(instance)) };
+}
+
+#[test]
+fn pinned_try_new_recover_heads() {
+    let result = PinnedFieldsTryBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 3,
+        first_word_builder: |text| Ok(first_word(text)),
+        counter_ref_builder: |_counter| Err(56),
+    }
+    .try_build_or_recover();
+    if let Err((56, heads)) = result {
+        assert_eq!(heads.text, "Hello world!");
+        assert_eq!(heads.counter, 3);
+    } else {
+        panic!("Test failed.");
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn pinned_builder_panic_drops_stored_fields() {
+    let tracker = Rc::new(());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        PinnedTracked::new(
+            "Hello world!".to_owned(),
+            tracker.clone(),
+            |text| CheckedWords(text.split(' ').collect()),
+            |_tracker| panic!("The builder of tracker_ref panicked."),
+        )
+    }));
+    assert!(result.is_err());
+    assert_eq!(Rc::strong_count(&tracker), 1);
+}
+
+// Miri crashes with Pin<Box<Future>> types due to
+// https://github.com/rust-lang/miri/issues/1038
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[tokio::test]
+async fn pinned_async_send() {
+    let instance = PinnedFieldsAsyncSendBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 0,
+        first_word_builder: |text| Box::pin(async move { first_word(text) }),
+        counter_ref_builder: |counter| Box::pin(async move { counter }),
+    }
+    .build()
+    .await;
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn single_allocation_builder_panic_drops_stored_fields() {
+    let tracker = Rc::new(());
+    let built = SingleAllocationTracked::new(
+        "Hello world!".to_owned(),
+        tracker.clone(),
+        |text| CheckedWords(text.split(' ').collect()),
+        |tracker| tracker,
+    );
+    built.with_words(|words| assert_eq!(words.0, ["Hello", "world!"]));
+    assert_eq!(Rc::strong_count(built.borrow_tracker_ref()), 2);
+    drop(built);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        SingleAllocationTracked::new(
+            "Hello world!".to_owned(),
+            tracker.clone(),
+            |text| CheckedWords(text.split(' ').collect()),
+            |_tracker| panic!("The builder of tracker_ref panicked."),
+        )
+    }));
+    assert!(result.is_err());
+    assert_eq!(Rc::strong_count(&tracker), 1);
+}
+
+#[test]
+fn single_allocation() {
+    let mut instance = SingleAllocationBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 0,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| first_word(text),
+        first_word_ref_builder: |first_word| first_word,
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    assert_eq!(**instance.borrow_first_word_ref(), "Hello");
+    assert!(core::ptr::eq(
+        *instance.borrow_first_word(),
+        &instance.borrow_text()[..5]
+    ));
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    instance.with_mut(|fields| {
+        **fields.counter_ref += 1;
+        fields.label.push('!');
+    });
+    instance.with(|fields| assert_eq!(**fields.counter_ref, 2));
+    let moved = Box::new(instance);
+    assert_eq!(*moved.borrow_first_word(), "Hello");
+    let heads = moved.into_heads();
+    assert_eq!(heads.text, "Hello world!");
+    assert_eq!(heads.counter, 2);
+    assert_eq!(heads.label, "greeting!");
+}
+
+#[test]
+fn single_allocation_try_new_recover_heads() {
+    let result = SingleAllocationTryBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 3,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| Ok(first_word(text)),
+        first_word_ref_builder: |first_word| Ok(first_word),
+        counter_ref_builder: |_counter| Err(56),
+    }
+    .try_build_or_recover();
+    if let Err((56, heads)) = result {
+        assert_eq!(heads.text, "Hello world!");
+        assert_eq!(heads.counter, 3);
+        assert_eq!(heads.label, "greeting");
+    } else {
+        panic!("Test failed.");
+    }
+}
+
+#[test]
+fn borrow_field_mut() {
+    fn relabel(instance: &mut SingleAllocation, suffix: &str) -> Result<usize, ()> {
+        let label = instance.borrow_label_mut();
+        if suffix.is_empty() {
+            return Err(());
+        }
+        label.push_str(suffix);
+        Ok(label.len())
+    }
+
+    let mut instance = SingleAllocationBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 0,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| first_word(text),
+        first_word_ref_builder: |first_word| first_word,
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    assert_eq!(relabel(&mut instance, "!"), Ok(9));
+    assert_eq!(relabel(&mut instance, ""), Err(()));
+    assert_eq!(instance.borrow_label(), "greeting!");
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+}
+
+#[test]
+fn reclaim() {
+    let mut instance = BoxAndMutRef::new(1, |data| data);
+    instance.reclaim_data(|data| {
+        *data += 1;
+        data
+    });
+    instance.with_dref_mut(|dref| **dref += 1);
+    assert_eq!(instance.into_heads().data, 3);
+
+    let mut instance = PinnedFields::new(
+        "Hello world!".to_owned(),
+        1,
+        |text| first_word(text),
+        |counter| counter,
+    );
+    instance.as_mut().reclaim_counter(|counter| {
+        *counter *= 10;
+        counter
+    });
+    instance
+        .as_mut()
+        .with_counter_ref_mut(|counter| **counter += 1);
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+    assert_eq!(instance.into_heads().counter, 11);
+
+    let mut instance = SingleAllocationBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 1,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| first_word(text),
+        first_word_ref_builder: |first_word| first_word,
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    instance.reclaim_counter(|counter| {
+        *counter = 5;
+        counter
+    });
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    assert_eq!(**instance.borrow_first_word_ref(), "Hello");
+    assert_eq!(instance.into_heads().counter, 6);
+
+    let mut instance = StableDerefMut::new(Box::new(1), |counter: &mut i32| counter);
+    instance.reclaim_counter(|counter: &mut i32| {
+        *counter = 41;
+        counter
+    });
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    let moved = Box::new(instance);
+    assert_eq!(*moved.into_heads().counter, 42);
+}
+
+#[test]
+fn replace_and_rebuild() {
+    let mut instance = ChainedAndUndocumented::new(1, |data| data, |ref1| ref1);
+    let old_data = instance.replace_data(2, |data| data, |ref1| ref1);
+    assert_eq!(old_data, 1);
+    assert_eq!(***instance.borrow_ref2(), 2);
+    instance.rebuild_ref1(|_data| &STATIC_INT, |ref1| ref1);
+    assert_eq!(***instance.borrow_ref2(), STATIC_INT);
+    instance.rebuild_ref2(|ref1| ref1);
+    assert_eq!(instance.into_heads().data, 2);
+
+    let mut instance = PinnedFields::new(
+        "Hello world!".to_owned(),
+        1,
+        |text| first_word(text),
+        |counter| counter,
+    );
+    let old_text = instance
+        .as_mut()
+        .replace_text("Goodbye world!".to_owned(), |text| first_word(text));
+    assert_eq!(old_text, "Hello world!");
+    assert_eq!(*instance.borrow_first_word(), "Goodbye");
+    instance.as_mut().rebuild_counter_ref(|counter| counter);
+    instance
+        .as_mut()
+        .with_counter_ref_mut(|counter| **counter += 1);
+    assert_eq!(instance.into_heads().counter, 2);
+
+    let mut instance = SingleAllocationBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 1,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| first_word(text),
+        first_word_ref_builder: |first_word| first_word,
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    instance.replace_text(
+        "Goodbye world!".to_owned(),
+        |text| first_word(text),
+        |first_word| first_word,
+    );
+    assert_eq!(instance.replace_counter(5, |counter| counter), 1);
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    assert_eq!(**instance.borrow_first_word_ref(), "Goodbye");
+    assert_eq!(instance.into_heads().counter, 6);
+
+    let mut instance = StableDerefFieldsBuilder {
+        text: "Hello world!".to_owned(),
+        numbers: vec![1, 2, 3],
+        name: Rc::from("numbers"),
+        first_word_builder: |text: &str| first_word(text),
+        numbers_tail_builder: |numbers: &[i32]| numbers_tail(numbers),
+        name_ref_builder: |name: &str| name,
+    }
+    .build();
+    let old_numbers = instance.replace_numbers(vec![4, 5], |numbers: &[i32]| numbers_tail(numbers));
+    assert_eq!(old_numbers, vec![1, 2, 3]);
+    let moved = Box::new(instance);
+    assert_eq!(*moved.borrow_numbers_tail(), [5]);
+    assert_eq!(*moved.borrow_first_word(), "Hello");
+}
+
+#[test]
+fn try_rebuild() {
+    let mut instance = ChainedAndUndocumented::new(1, |data| data, |ref1| ref1);
+    let result = instance.try_rebuild_ref2(|_ref1| Err(56));
+    assert_eq!(result, Err(56));
+    assert_eq!(***instance.borrow_ref2(), 1);
+    instance
+        .try_rebuild_ref2(|ref1| Result::<_, ()>::Ok(ref1))
+        .unwrap();
+    assert_eq!(***instance.borrow_ref2(), 1);
+
+    let mut instance = StableDerefFieldsBuilder {
+        text: "Hello world!".to_owned(),
+        numbers: vec![1, 2, 3],
+        name: Rc::from("numbers"),
+        first_word_builder: |text: &str| first_word(text),
+        numbers_tail_builder: |numbers: &[i32]| numbers_tail(numbers),
+        name_ref_builder: |name: &str| name,
+    }
+    .build();
+    let result = instance.try_rebuild_first_word(|text: &str| text.split('!').next().ok_or(()));
+    assert_eq!(result, Ok(()));
+    assert_eq!(*instance.borrow_first_word(), "Hello world");
+}
+
+// Miri crashes with Pin<Box<Future>> types due to
+// https://github.com/rust-lang/miri/issues/1038
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[tokio::test]
+async fn rebuild_async() {
+    let mut instance = PinnedFields::new(
+        "Hello world!".to_owned(),
+        1,
+        |text| first_word(text),
+        |counter| counter,
+    );
+    instance
+        .as_mut()
+        .rebuild_first_word_async(|text| Box::pin(async move { &text[6..] }))
+        .await;
+    assert_eq!(*instance.borrow_first_word(), "world!");
+}
+
+#[test]
+fn into_parts() {
+    let instance = ExtractableFieldsBuilder {
+        text: "Hello world!".to_owned(),
+        word_count_builder: |text| text.split(' ').count(),
+        first_word_builder: |text| first_word(text),
+        word_count_ref_builder: |word_count| word_count,
+        first_word_len_builder: |first_word| first_word.len(),
+    }
+    .build();
+    assert_eq!(**instance.borrow_word_count_ref(), 2);
+    let parts = instance.into_parts();
+    assert_eq!(parts.text, "Hello world!");
+    assert_eq!(parts.word_count, 2);
+    assert_eq!(parts.first_word_len, 5);
+
+    let instance = SingleAllocationBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 3,
+        label: "greeting".to_owned(),
+        first_word_builder: |text| first_word(text),
+        first_word_ref_builder: |first_word| first_word,
+        counter_ref_builder: |counter| counter,
+    }
+    .build();
+    let parts = instance.into_parts();
+    assert_eq!(parts.text, "Hello world!");
+    assert_eq!(parts.counter, 3);
+}
+
+#[test]
+fn map_field() {
+    let split = SplitText::new(
+        "Hello wonderful world!".to_owned(),
+        |text| split_words(text),
+        |text| first_word(text),
+    );
+    let text_box = split.borrow_text() as *const String;
+    let longest: LongestWord = split.map_words(|_text, words| {
+        words
+            .into_iter()
+            .max_by_key(|word| word.len())
+            .unwrap_or_default()
+    });
+    assert_eq!(longest.borrow_text() as *const String, text_box);
+    assert_eq!(*longest.borrow_longest(), "wonderful");
+    let moved = Box::new(longest);
+    assert_eq!(moved.into_heads().text, "Hello wonderful world!");
+}
+
+/// Reads the text it borrows when dropped, so that Miri catches it outliving its cart.
+struct CheckedWords<'a>(Vec<&'a str>);
+
+impl Drop for CheckedWords<'_> {
+    fn drop(&mut self) {
+        assert!(self.0.iter().all(|word| !word.is_empty()));
+    }
+}
+
+unsafe impl<'a> ouroboros::Covariant<'a> for CheckedWords<'static> {
+    type Output = CheckedWords<'a>;
+
+    fn shorten(&'a self) -> &'a CheckedWords<'a> {
+        self
+    }
+
+    fn shorten_owned(self) -> CheckedWords<'a> {
+        self
+    }
+
+    unsafe fn lengthen(from: CheckedWords<'a>) -> Self {
+        core::mem::transmute(from)
+    }
+}
+
+#[test]
+fn owned() {
+    use ouroboros::Owned;
+
+    let text: Owned<String, &'static str> =
+        Owned::attach_to_cart("  Hello wonderful world!  ".to_owned(), |text| text.trim());
+    assert_eq!(*text.get(), "Hello wonderful world!");
+    let cart = text.backing_cart() as *const String;
+    let words: Owned<String, CheckedWords<'static>> =
+        text.map_project(|trimmed, _| CheckedWords(trimmed.split(' ').collect()));
+    assert_eq!(words.get().0, ["Hello", "wonderful", "world!"]);
+    assert_eq!(words.backing_cart() as *const String, cart);
+    let moved = Box::new(words);
+    let lengths: Owned<String, Vec<&'static str>> =
+        moved.map_project(|words, text| vec![&text[..2], words.0[1]]);
+    assert_eq!(*lengths.get(), ["  ", "wonderful"]);
+    assert_eq!(lengths.into_backing_cart(), "  Hello wonderful world!  ");
+}
+
+#[test]
+fn owned_try_map_project() {
+    use ouroboros::Owned;
+
+    let words: Owned<String, Vec<&'static str>> =
+        Owned::attach_to_cart("Hello world!".to_owned(), |text| text.split(' ').collect());
+    let failed: Result<Owned<String, &'static str>, usize> =
+        words.try_map_project(|words, _| words.into_iter().find(|word| word.len() > 6).ok_or(5));
+    assert_eq!(failed.err(), Some(5));
+    let words: Owned<String, Vec<&'static str>> =
+        Owned::try_attach_to_cart("Hello world!".to_owned(), |text| {
+            Ok::<_, ()>(text.split(' ').collect())
+        })
+        .unwrap();
+    let last: Owned<String, Option<&'static str>> = words
+        .try_map_project(|words, _| Ok::<_, ()>(words.last().copied()))
+        .unwrap();
+    assert_eq!(*last.get(), Some("world!"));
+}
+
+#[test]
+fn owning_ref() {
+    use ouroboros::OwningRef;
+
+    let text = OwningRef::new("Hello wonderful world!".to_owned());
+    let owner = text.as_owner() as *const String;
+    let words = text.map(|text| &text[6..]);
+    let moved = Box::new(words);
+    let word = moved.map(|words| &words[..9]);
+    assert_eq!(&*word, "wonderful");
+    assert_eq!(word.as_owner() as *const String, owner);
+    let failed = word.try_map(|word| word.strip_prefix("world").ok_or(3));
+    assert_eq!(failed.err(), Some(3));
+    let numbers = OwningRef::new([1, 2, 3, 4]);
+    let middle = numbers
+        .try_map(|numbers| Ok::<_, ()>(&numbers[1..3]))
+        .unwrap();
+    assert_eq!(*middle, [2, 3]);
+    assert_eq!(middle.into_owner(), [1, 2, 3, 4]);
+}
+
+#[test]
+fn owning_ref_mut() {
+    use ouroboros::OwningRefMut;
+
+    let mut numbers = OwningRefMut::new([1, 2, 3, 4]);
+    numbers[0] = 5;
+    let mut middle = numbers.map(|numbers| &mut numbers[1..3]);
+    middle[0] = 6;
+    let mut moved = Box::new(middle);
+    moved[1] = 7;
+    let mut last = moved.try_map(|middle| middle.last_mut().ok_or(())).unwrap();
+    *last += 1;
+    let last = last.into_owning_ref();
+    assert_eq!(*last.as_owner(), [5, 6, 8, 4]);
+    assert_eq!(*last, 8);
+    assert_eq!(last.into_owner(), [5, 6, 8, 4]);
+    let empty = OwningRefMut::new(Vec::<i32>::new());
+    let failed = empty.try_map(|numbers| numbers.first_mut().ok_or(0));
+    assert_eq!(failed.err(), Some(0));
+}
+
+#[test]
+fn auto_trait_asserts() {
+    fn is_send<T: Send>(_: &T) {}
+    fn is_sync<T: Sync>(_: &T) {}
+
+    let mut counter = CounterInBlock::new(1, |counter| counter);
+    counter.with_counter_ref_mut(|counter_ref| **counter_ref += 1);
+    is_send(&counter);
+    is_sync(&counter);
+    assert_eq!(counter.into_heads().counter, 2);
+    let cell = PinnedCell::new(core::cell::Cell::new(1), |cell| cell);
+    is_send(&cell);
+    assert_eq!(cell.into_heads().cell.get(), 1);
+    let pinned = PinnedFields::new(
+        "Hello world!".to_owned(),
+        0,
+        |text| first_word(text),
+        |counter| counter,
+    );
+    is_sync(&pinned);
+    let shared = SendAndSync::new(vec![1, 2], |items| items.first());
+    is_send(&shared);
+    is_sync(&shared);
+    assert_eq!(shared.borrow_first(), &Some(&1));
+    let text = SharedText::new("Hello world!".to_owned(), |text| first_word(text));
+    is_send(&text);
+    is_sync(&text);
+    assert_eq!(*text.borrow_first_word(), "Hello");
+}
+
+#[test]
+fn stable_deref() {
+    let instance = StableDerefFieldsBuilder {
+        text: "Hello world!".to_owned(),
+        numbers: vec![1, 2, 3],
+        name: Rc::from("numbers"),
+        first_word_builder: |text: &str| first_word(text),
+        numbers_tail_builder: |numbers: &[i32]| numbers_tail(numbers),
+        name_ref_builder: |name: &str| name,
+    }
+    .build();
+    let text_ptr = instance.borrow_text().as_ptr();
+    let moved = Box::new(instance);
+    assert_eq!(moved.borrow_text().as_ptr(), text_ptr);
+    assert_eq!(*moved.borrow_first_word(), "Hello");
+    assert_eq!(*moved.borrow_numbers_tail(), [2, 3]);
+    assert_eq!(*moved.borrow_name_ref(), "numbers");
+    let cloned = StableDerefFields::clone(&moved);
+    assert_eq!(cloned, *moved);
+    assert_ne!(cloned.borrow_text().as_ptr(), text_ptr);
+    assert_eq!(cloned.borrow_name().as_ptr(), moved.borrow_name().as_ptr());
+    let heads = cloned.into_heads();
+    assert_eq!(heads.text, "Hello world!");
+    assert_eq!(heads.numbers, vec![1, 2, 3]);
+    assert_eq!(&*heads.name, "numbers");
+    assert_eq!(Rc::strong_count(&heads.name), 2);
+
+    let mut instance = StableDerefMut::new(Box::new(41), |counter: &mut i32| counter);
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    let moved = Box::new(instance);
+    assert_eq!(*moved.into_heads().counter, 42);
+}
+
+#[test]
+fn detected_stable_deref() {
+    let instance = DetectedStableDeref::new(
+        "Hello world!".to_owned(),
+        Rc::new(1),
+        Rc::new(2),
+        |text: &str, shared: &i32| (first_word(text), shared),
+        |counter: &mut Rc<i32>| counter,
+    );
+    let text_ptr = instance.borrow_text().as_ptr();
+    let moved = Box::new(instance);
+    assert_eq!(moved.borrow_text().as_ptr(), text_ptr);
+    assert_eq!(*moved.borrow_first_word(), ("Hello", &1));
+    assert_eq!(*moved.into_heads().counter, 2);
+}
+
+#[test]
+fn custom_allocator() {
+    let allocator = CountingAllocator::default();
+    let mut instance = CustomAllocatedBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 1,
+        first_word_builder: |text| first_word(text),
+        counter_ref_builder: |counter| counter,
+        allocator: allocator.clone(),
+    }
+    .build();
+    assert_eq!(allocator.live.get(), 2);
+    instance.with_counter_ref_mut(|counter| **counter += 1);
+    let moved = Box::new(instance);
+    assert_eq!(*moved.borrow_first_word(), "Hello");
+    let heads = moved.into_heads();
+    assert_eq!(allocator.live.get(), 0);
+    assert_eq!(heads.text, "Hello world!");
+    assert_eq!(heads.counter, 2);
+
+    let instance = CustomAllocated::new(
+        "Hello world!".to_owned(),
+        1,
+        |text| first_word(text),
+        |counter| counter,
+        allocator.clone(),
+    );
+    drop(instance);
+    assert_eq!(allocator.live.get(), 0);
+}
+
+#[test]
+fn custom_allocator_with_arrow_in_type() {
+    let instance = TaggedAllocated::new(
+        "Hello world!".to_owned(),
+        |text| first_word(text),
+        TaggedAllocator(core::marker::PhantomData),
+    );
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+}
+
+#[test]
+fn custom_allocator_try_new_recover_heads() {
+    let allocator = CountingAllocator::default();
+    let result = CustomAllocated::try_new_or_recover(
+        "Hello world!".to_owned(),
+        3,
+        |text| Ok(first_word(text)),
+        |_counter| Err(56),
+        allocator.clone(),
+    );
+    if let Err((56, heads)) = result {
+        assert_eq!(allocator.live.get(), 0);
+        assert_eq!(heads.text, "Hello world!");
+        assert_eq!(heads.counter, 3);
+    } else {
+        panic!("Test failed.");
+    }
+}
+
+// Miri crashes with Pin<Box<Future>> types due to
+// https://github.com/rust-lang/miri/issues/1038
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[tokio::test]
+async fn custom_allocator_async_new() {
+    let allocator = CountingAllocator::default();
+    let future_allocator = allocator.clone();
+    let instance = CustomAllocatedAsyncBuilder {
+        text: "Hello world!".to_owned(),
+        counter: 1,
+        first_word_builder: |text| {
+            let future = allocator_api2::boxed::Box::new_in(
+                async move { first_word(text) },
+                future_allocator.clone(),
+            );
+            allocator_api2::boxed::Box::into_pin(allocator_api2::unsize_box!(future))
+        },
+        counter_ref_builder: |counter| {
+            let future = allocator_api2::boxed::Box::new_in(
+                async move { counter },
+                future_allocator.clone(),
+            );
+            allocator_api2::boxed::Box::into_pin(allocator_api2::unsize_box!(future))
+        },
+        allocator: allocator.clone(),
+    }
+    .build()
+    .await;
+    assert_eq!(allocator.live.get(), 2);
+    assert_eq!(*instance.borrow_first_word(), "Hello");
+    drop(instance);
+    assert_eq!(allocator.live.get(), 0);
+}
+
+#[test]
+fn self_referencing_enum() {
+    let parsed: Document = DocumentParsedBuilder {
+        src: "Hello world!".to_owned(),
+        words_builder: |src| src.split(' ').collect(),
+    }
+    .build()
+    .into();
+    assert!(parsed.is_parsed());
+    assert!(!parsed.is_raw());
+    assert_eq!(parsed.as_parsed().unwrap().borrow_words()[1], "world!");
+    let word_count = |document: &Document| {
+        document.with_variant(|variant| match variant {
+            DocumentVariant::Raw(text) => text.split(' ').count(),
+            DocumentVariant::Parsed(fields) => fields.words.len(),
+            DocumentVariant::Pair(..) => 2,
+            DocumentVariant::Empty => 0,
+        })
+    };
+    assert_eq!(word_count(&parsed), 2);
+    let raw = Document::Raw("Hello to you too!".to_owned());
+    assert_eq!(raw.as_raw().unwrap(), "Hello to you too!");
+    assert_eq!(word_count(&raw), 4);
+    assert_eq!(Document::Pair(1, 2).as_pair(), Some((&1, &2)));
+    assert!(Document::Empty.is_empty());
+    assert_ne!(parsed, raw);
+    assert_eq!(
+        format!("{:?}", parsed),
+        r#"Parsed(DocumentParsed { src: "Hello world!", words: ["Hello", "world!"] })"#
+    );
+}
+
 #[test]
 fn box_and_mut_ref() {
     let mut bar = BoxAndMutRefBuilder {
@@ -274,8 +1667,8 @@ fn single_lifetime() {
 
     let external = "Hello world!".to_owned();
     let instance = Struct::new(&external, |field_ref| field_ref);
-    drop(instance.borrow_external());
-    drop(instance.borrow_internal());
+    let _ = instance.borrow_external();
+    let _ = instance.borrow_internal();
     drop(instance);
 }
 
@@ -302,8 +1695,82 @@ fn custom_ref() {
     assert_eq!(modified_data, "Goodbye world!");
 }
 
+// Async closures are stable since 1.85.
+#[cfg(all(not(feature = "miri"), feature = "std"))]
+#[rustversion::since(1.85)]
+mod async_fn_tests {
+    use super::*;
+
+    #[self_referencing(async_fn)]
+    struct AsyncFnWords {
+        text: String,
+        #[borrows(text)]
+        first_word: &'this str,
+        #[borrows(text)]
+        #[covariant]
+        words: Vec<&'this str>,
+        #[borrows(first_word, words)]
+        repeats: usize,
+    }
+
+    #[tokio::test]
+    async fn async_fn_build() {
+        let instance = AsyncFnWordsAsyncBuilder {
+            text: "a rose is a rose".to_owned(),
+            first_word_builder: async |text: &String| {
+                tokio::task::yield_now().await;
+                text.split(' ').next().unwrap()
+            },
+            words_builder: async |text: &String| text.split(' ').collect(),
+            repeats_builder: async |first_word: &&str, words: &Vec<&str>| {
+                words.iter().filter(|word| *word == first_word).count()
+            },
+        }
+        .build_concurrent()
+        .await;
+        assert_eq!(*instance.borrow_first_word(), "a");
+        assert_eq!(instance.borrow_words().len(), 5);
+        assert_eq!(*instance.borrow_repeats(), 2);
+    }
+
+    #[tokio::test]
+    async fn async_fn_try_build() {
+        let result = AsyncFnWordsAsyncTryBuilder {
+            text: "".to_owned(),
+            first_word_builder: async |text: &String| {
+                text.split(' ')
+                    .find(|word| !word.is_empty())
+                    .ok_or("no words")
+            },
+            words_builder: async |text: &String| Ok(text.split(' ').collect()),
+            repeats_builder: async |_first_word: &&str, words: &Vec<&str>| Ok(words.len()),
+        }
+        .try_build()
+        .await;
+        assert!(matches!(result, Err("no words")));
+    }
+
+    #[tokio::test]
+    async fn async_fn_rebuild() {
+        let mut instance = AsyncFnWords::new_async(
+            "a rose is a rose".to_owned(),
+            async |text: &String| text.split(' ').next().unwrap(),
+            async |text: &String| text.split(' ').collect(),
+            async |_first_word: &&str, words: &Vec<&str>| words.len(),
+        )
+        .await;
+        assert_eq!(*instance.borrow_repeats(), 5);
+        instance
+            .rebuild_repeats_async(async |first_word: &&str, words: &Vec<&str>| {
+                words.iter().filter(|word| *word == first_word).count()
+            })
+            .await;
+        assert_eq!(*instance.borrow_repeats(), 2);
+    }
+}
+
 #[cfg(not(feature = "miri"))]
-#[rustversion::stable(1.62)]
+#[rustversion::stable]
 mod compile_tests {
     /// Tests that all files in fail_tests fail to compile.
     #[test]
@@ -79,6 +79,38 @@
 /// indicate a field that will eventually borrow from the struct, but does not borrow anything when
 /// first created. For example, you could use this on a field like `error: Option<&'this str>`.
 ///
+/// A field with a `#[borrows()]` annotation can also take ownership of fields declared before it
+/// which are not borrowed by anything else, by naming them in `#[consumes()]`. Their values are
+/// passed to the builder after the borrowed fields, so `#[borrows(text)] #[consumes(config)]`
+/// requires a builder like `|text, config| ...`. A consumed field can be built from borrows of
+/// its own, in which case its builder runs right before the builder of the field consuming it,
+/// but it cannot consume other fields itself. Consumed fields are not stored in the struct, so
+/// they have no accessors and are not part of `Heads`, while the constructors still take their
+/// values or builders in the order the fields are declared. `Clone`,
+/// `Default` and `Deserialize` cannot be derived for structs with consumed fields, and methods
+/// which would have to call the builder again, like `rebuild_FIELD()`, are not generated for it.
+///
+/// Tuple structs are supported as well. Their fields are borrowed by index, so
+/// `struct Parsed(String, #[borrows(0)] &'this str)` declares a second field which borrows the
+/// first. Generated items which are named after a field use its index instead, E.G. `borrow_1()`
+/// or `with_0()`, and the builders are tuple structs taking their arguments in the same order as
+/// `new()`. Structs which hold the fields by name, like `Heads` or `BorrowedFields`, name them
+/// `_0`, `_1` and so on.
+///
+/// Enums can be self-referencing too. Each variant which contains a field with a `#[borrows()]`
+/// annotation is turned into its own self-referencing struct named after the enum and the variant,
+/// so the `Parsed { .. }` variant of `enum Document` is stored as a `DocumentParsed`, which is
+/// constructed and accessed like any other self-referencing struct and can be converted into the
+/// enum using `From`. Variants without borrows are kept as they are. The enum gets `is_variant()`
+/// and `as_variant()` methods for each variant, and a `with_variant()` method which provides a
+/// `DocumentVariant` holding references to the fields of the current variant, meant to be used
+/// with `match`. `DocumentVariant` holds the `BorrowedFields` of the variant structs, which are
+/// only visible to the module the enum is declared in unless `pub_extras` is used, so
+/// `with_variant()` and `DocumentVariant` follow the same rule instead of taking the visibility of
+/// the enum. A public enum therefore needs `#[self_referencing(pub_extras)]` for `with_variant()`
+/// to be usable outside of that module. At least one variant must contain a borrowing field, and
+/// such a variant cannot be named `Variant`, as its struct would collide with `DocumentVariant`.
+///
 /// # You must comply with these limitations
 /// - Fields must be declared before the first time they are borrowed.
 /// - Normal borrowing rules apply, E.G. a field cannot be borrowed mutably twice.
@@ -250,6 +282,35 @@
 /// }
 /// ```
 ///
+/// # Async closures
+/// On Rust 1.85 and later, `#[self_referencing(async_fn)]` makes the builders of
+/// `MyStructAsyncBuilder` and `MyStructAsyncTryBuilder` (and of `new_async()`, `try_new_async()`,
+/// `rebuild_FIELD_async()` and so on) `AsyncFnOnce` closures. They can be written as
+/// `async |int_data: &i32| int_data` instead of returning a `Pin<Box<dyn Future>>`, so the futures
+/// are neither allocated nor dynamically dispatched. There is no way to require the future of an
+/// `AsyncFnOnce` to be `Send`, so the Send variants keep taking boxed futures.
+///
+/// ```ignore
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing(async_fn)]
+/// struct MyStruct {
+///     int_data: i32,
+///     #[borrows(int_data)]
+///     int_reference: &'this i32,
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let my_value = MyStructAsyncBuilder {
+///         int_data: 42,
+///         int_reference_builder: async |int_data: &i32| int_data,
+///     }.build().await;
+///     // Prints 42
+///     println!("{:?}", my_value.borrow_int_reference());
+/// }
+/// ```
+///
 /// # What does the macro generate?
 /// The `#[self_referencing]` struct will replace your definition with an unsafe self-referencing
 /// struct with a safe public interface. Many functions will be generated depending on your original
@@ -267,6 +328,108 @@
 /// `new()` and `with()`.) You can use `#[self_referencing(pub_extras)]` to make these items have the
 /// same visibility as the struct itself.
 ///
+/// ### Storing borrowed fields inline
+/// By default every borrowed field is kept in its own heap allocation so that it stays in place
+/// when the struct is moved. `#[self_referencing(pinned)]` instead stores all fields inside the
+/// struct itself, which then must never move. Constructors return a `Pin<Box<MyStruct>>`,
+/// allocating only once, and the sync builder also provides `MyStruct::new_in_place()` and
+/// `build_in_place()` which construct the struct in a `Pin<&mut MaybeUninit<MyStruct>>` without
+/// allocating at all. A struct constructed in place is not dropped automatically. Methods
+/// which mutate the struct, like `with_mut()`, take `self: Pin<&mut Self>`, and `into_heads()`
+/// takes the `Pin<Box<Self>>`. `Clone`, `Default` and `Deserialize` cannot be derived for pinned
+/// structs.
+///
+/// ### Storing borrowed fields in a single allocation
+/// `#[self_referencing(single_allocation)]` is a middle ground which keeps the struct movable.
+/// All borrowed fields are stored together in one heap allocation instead of one allocation
+/// each, so constructing the struct allocates only once no matter how many fields are borrowed.
+/// Everything else, including the generated API, stays the same as for ordinary structs. It
+/// cannot be combined with `pinned`.
+///
+/// ### Borrowing through the contents of containers
+/// A borrowed field whose type is `Box<T>`, `Arc<T>`, `Rc<T>`, `Vec<T>` or `String` already keeps
+/// its contents in a place that does not move along with it. Marking such a field with
+/// `#[stable_deref]` stores it without the extra box, and only its contents can then be borrowed:
+/// builders of fields borrowing it receive a `&T`, `&[T]` or `&str` instead of a reference to the
+/// container, and so do the accessors of the field itself. `Arc` and `Rc` cannot be mutably
+/// borrowed this way. Other fields and `into_heads()` are not affected. A derived `Clone` clones
+/// the container, so the contents of `Arc` and `Rc` are shared with the clone. Fields of these
+/// types are not detected by default, since that would change the argument types of the builders
+/// of existing structs. `#[self_referencing(stable_deref)]` opts into detecting them, storing every
+/// borrowed field of these types as if it was marked `#[stable_deref]`, except for mutably
+/// borrowed `Arc` and `Rc` fields, which stay boxed. The detection only looks at the name of the
+/// type. Like the attribute, it cannot be combined with `pinned` or `single_allocation`.
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing]
+/// struct Document {
+///     #[stable_deref]
+///     text: String,
+///     #[borrows(text)]
+///     #[covariant]
+///     words: Vec<&'this str>,
+/// }
+///
+/// let document = DocumentBuilder {
+///     text: "Hello world!".to_owned(),
+///     words_builder: |text: &str| text.split(' ').collect(),
+/// }.build();
+/// assert_eq!(document.borrow_text().len(), 12);
+/// assert_eq!(document.borrow_words()[1], "world!");
+/// ```
+///
+/// ### Boxing borrowed fields with a custom allocator
+/// With the `allocator_api2` feature enabled, `#[self_referencing(allocator = MyAlloc)]` boxes
+/// borrowed fields using an allocator implementing
+/// [`allocator_api2::alloc::Allocator`](https://docs.rs/allocator-api2) and `Clone`. Constructors
+/// and builders take the allocator as an additional last argument named `allocator`, which is
+/// cloned once for every borrowed field. The futures returned by the builders of async
+/// constructors are `Pin<allocator_api2::boxed::Box<dyn Future<...>, MyAlloc>>`, which can be
+/// created with `allocator_api2::unsize_box!` and `Box::into_pin`. This option cannot be combined
+/// with `pinned` or `single_allocation`, and `Clone`, `Default` and `Deserialize` cannot be
+/// derived.
+///
+/// ### Send and Sync
+/// The struct implements `Send` and `Sync` exactly when all of its fields do, with `'this`
+/// treated like any other lifetime, so a `&'this T` field requires `T: Sync` for both. Boxing a
+/// borrowed field does not change this, except that a custom allocator must also be `Send` or
+/// `Sync`. Generic parameters which are not used by any field count as if they were stored in the
+/// struct. This also holds for `pinned` and `single_allocation` structs, which keep mutably
+/// borrowed fields in an `UnsafeCell` only ever accessed through `&mut self` or by the builders
+/// of the fields borrowing them. `#[self_referencing(assert_send)]` and
+/// `#[self_referencing(assert_sync)]` check at compile time that the struct implements these
+/// traits and report an error at every field which prevents it. For generic structs, the check
+/// assumes nothing but the bounds declared on the struct.
+///
+/// ### Iterating over a field
+/// Annotating one field which no other field borrows with `#[iterator]` implements `Iterator`
+/// for the struct, advancing that field with `with_FIELD_mut()`. This lets a function return an
+/// iterator together with the data it iterates over, without boxing it:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing]
+/// struct OwningNames {
+///     names: Vec<String>,
+///     #[borrows(names)]
+///     #[covariant]
+///     #[iterator]
+///     remaining: std::iter::Cloned<std::slice::Iter<'this, String>>,
+/// }
+///
+/// fn owning_names(names: Vec<String>) -> impl Iterator<Item = String> {
+///     OwningNamesBuilder {
+///         names,
+///         remaining_builder: |names| names.iter().cloned(),
+///     }.build()
+/// }
+/// ```
+/// `Iterator` cannot lend items which borrow from the iterator itself, so the items must not use
+/// `'this`. A field of type `std::slice::Iter<'this, String>` fails to compile, since its items
+/// would outlive the struct they point into. Map them to owned values as above instead. Pinned
+/// structs are only mutably accessible through `Pin`, so they cannot use `#[iterator]`.
+///
 /// # List of generated items
 /// ### `MyStruct::new(fields...) -> MyStruct`
 /// A basic constructor. It accepts values for each field in the order you declared them in. For
@@ -285,6 +448,12 @@
 /// ### `MyStruct::new_async_send(fields...) -> MyStruct`
 /// An async send constructor. It works identically to the sync constructor differing only in the
 /// Send trait being specified in the return type.
+/// ### `MyStruct::new_async_concurrent(fields...) -> MyStruct`
+/// Like `new_async()`, but the futures of builders which do not borrow each other, directly or
+/// through other fields, are awaited concurrently. Builders run in levels computed from the
+/// `#[borrows()]` annotations: every builder which only borrows **head fields** runs in the first
+/// level, and a builder borrowing a field from some level runs in a later one. There is a
+/// `new_async_send_concurrent()` version with the Send trait specified in the return type as well.
 /// ### `MyStructBuilder`
 /// This is the preferred way to create a new instance of your struct. It is similar to using the
 /// `MyStruct { a, b, c, d }` syntax instead of `MyStruct::new(a, b, c, d)`. It contains one field
@@ -297,9 +466,11 @@
 /// ### `MyStructAsyncBuilder`
 /// This is the preferred way to asynchronously create a new instance of your struct. It works
 /// identically to the synchronous builder differing only in the type of closures it expects. In
-/// particular, all builder functions are called serially in the order that they were declared.
-/// Whenever a closure is required it is expected to return a Pinned and Boxed Future that Outputs
-/// the same type as the synchronous version.
+/// particular, `.build()` calls all builder functions serially in the order that they were
+/// declared. Calling `.build_concurrent()` instead uses `new_async_concurrent()`, which awaits the
+/// builders of fields that do not borrow each other at the same time. Whenever a closure is
+/// required it is expected to return a Pinned and Boxed Future that Outputs the same type as the
+/// synchronous version.
 /// ### `MyStructAsyncSendBuilder`
 /// Same as MyStructAsyncBuilder, but with Send trait specified in the return type.
 /// ### `MyStruct::try_new<E>(fields...) -> Result<MyStruct, E>`
@@ -320,6 +491,13 @@
 /// `MyStructAsyncTryBuilder` and its `try_build_or_recover()` function.
 /// ### `MyStruct::try_new_or_recover_async_send<E>(fields...) -> Result<MyStruct, (E, Heads)>`
 /// Same as `try_new_or_recover_async()` function, but with Send trait specified in the return type.
+/// ### `MyStruct::try_new_cancellable_async<E>(fields..., on_cancel: FnOnce(Heads)) -> Result<MyStruct, (E, Heads)>`
+/// Similar to `try_new_or_recover_async()`, except that the **head fields** are also recovered
+/// if the returned future is dropped before it completes. In that case the builder which was
+/// being awaited and the fields built so far are dropped, then `on_cancel` is called with the
+/// heads. It is called through `MyStructAsyncTryBuilder::build_cancellable(on_cancel)`. Pinned and
+/// `single_allocation` structs store their heads in place, so this function is not generated for
+/// them. There is a `try_new_cancellable_async_send()` version as well.
 /// ### `MyStruct::with_FIELD<R>(&self, user: FnOnce(field: &FieldType) -> R) -> R`
 /// This function is generated for every **tail and immutably-borrowed field** in your struct. It
 /// allows safely accessing
@@ -330,11 +508,18 @@
 /// is equivalent to calling `my_struct.with_FIELD(|field| field)`. It is only generated for types
 /// which are known to be covariant, either through the macro being able to detect it or through the
 /// programmer adding the `#[covariant]` annotation to the field.
-/// There is no `borrow_FIELD_mut`, unfortunately, as Rust's
-/// borrow checker is currently not capable of ensuring that such a method would be used safely.
 /// ### `MyStruct::with_FIELD_mut<R>(&mut self, user: FnOnce(field: &mut FieldType) -> R) -> R`
 /// This function is generated for every **tail field** in your struct. It is the mutable version
 /// of `with_FIELD`.
+/// ### `MyStruct::borrow_FIELD_mut(&mut self) -> &mut FieldType`
+/// This function is generated for every **tail field** which does not borrow other fields, such as
+/// a counter stored next to them. It returns a plain mutable reference, which makes `?` and early
+/// returns usable where `with_FIELD_mut` would need a closure. Fields with a `#[borrows()]`
+/// annotation do not get this function, even when they are covariant: assigning a reference
+/// which lives shorter than the struct to such a field would leave it dangling once that
+/// reference expires, and only the closure of `with_FIELD_mut` can prevent it. A guard type whose
+/// `DerefMut` target has `'this` shortened to the guard's lifetime does not help either, since
+/// that shortened lifetime is exactly what would let such a reference be stored.
 /// ### `MyStruct::with<R>(&self, user: FnOnce(fields: AllFields) -> R) -> R`
 /// Allows borrowing all **tail and immutably-borrowed fields** at once. Functions similarly to
 /// `with_FIELD`.
@@ -344,15 +529,575 @@
 /// fields as mutable at the same time and also have immutable access to any remaining fields.
 /// ### `MyStruct::into_heads(self) -> Heads`
 /// Drops all self-referencing fields and returns a struct containing all **head fields**.
+/// ### `MyStruct::into_parts(self) -> Parts`
+/// Like `into_heads`, but the returned struct also contains every **tail field** whose type does
+/// not use the `'this` lifetime, such as a length computed from a borrowed field. Self-referencing
+/// fields which are borrowed by other fields can be included as well by annotating them with
+/// `#[extractable]`, as long as their type does not use `'this` either.
+/// ### `MyStruct::reclaim_FIELD(&mut self, rebuild: FnOnce(field: &mut FieldType, ...) -> BorrowerType)`
+/// This function is generated for every field which is **mutably borrowed** by a **tail field**.
+/// It drops the field borrowing it, gives `rebuild` mutable access to the field again along with
+/// the other fields the borrower needs, and stores what `rebuild` returns as the new value of the
+/// borrower. If `rebuild` panics, the process is aborted.
+/// ### `MyStruct::replace_FIELD(&mut self, value: FieldType, dependent_builders...) -> FieldType`
+/// This function is generated for every **head field** which is borrowed by other fields. It drops
+/// every field which borrows the head field, directly or through other fields, moves `value` into
+/// the head field in place of its old value and then calls the builders of the dropped fields in
+/// the order they were declared in, just like `new()` does. The old value is returned. If a builder
+/// panics, the process is aborted.
+/// ### `MyStruct::rebuild_FIELD(&mut self, builder, dependent_builders...)`
+/// This function is generated for every **self-referencing field**. It works like `replace_FIELD`,
+/// except that the field itself is recreated by calling `builder` as well.
+/// ### `MyStruct::map_FIELD<Other>(self, map: FnOnce(head: &HeadType, field: FieldType) -> OtherField) -> Other`
+/// This function is generated for every **tail field** of structs with exactly one **head field**
+/// which is also the only field borrowed by other fields, as in
+/// `{ src: String, #[borrows(src)] ast: Ast<'this> }`. It consumes the struct and creates an
+/// instance of `Other`, another self-referencing struct made of a head field of the same type
+/// followed by a single field borrowing it, such as `{ src: String, #[borrows(src)] symbols:
+/// Symbols<'this> }`. `map` receives the head field and the value of `FIELD` and returns the
+/// value of the second field of `Other`. The box holding the head field is moved into `Other`
+/// as is, so nothing is allocated or copied. `Other` must not have generic parameters.
+/// ### `MyStruct::try_rebuild_FIELD<E>(&mut self, builder) -> Result<(), E>`
+/// This function is generated for every **tail field** which only borrows other fields immutably.
+/// `builder` is called like the builder of the field in `try_new()`. The old value is only dropped
+/// and replaced once it returns `Ok`, so the struct is left unchanged when it returns an error.
+/// ### `MyStruct::rebuild_FIELD_async(&mut self, builder)`
+/// Generated for the same fields as `try_rebuild_FIELD`, but infallible. `builder` returns a
+/// pinned and boxed future which outputs the new value of the field, like the builders of
+/// `new_async()`. The old value is only replaced once that future completes, so the struct is left
+/// unchanged if the future returned by this function is dropped before it completes.
+///
+/// # Derives
+/// `Debug`, `PartialEq`, `Eq`, `Hash`, `PartialOrd` and `Ord` can be derived. They print, compare
+/// or hash every field which is not borrowed mutably, in the order the fields were declared in.
+/// A field annotated with `#[ouroboros(skip)]` is left out of all of them, which is useful for
+/// large buffers or handles which do not implement the trait. `#[debug(skip)]` only leaves the
+/// field out of `Debug`, and `#[debug(with = path::to_fn)]` formats the field by calling a
+/// function with the signature `fn(&FieldType, &mut Formatter) -> fmt::Result` instead.
+/// `Clone` can be derived for structs without mutably borrowed fields. It clones the **head
+/// fields** and then recreates each **self-referencing field** from the clones, either by calling
+/// the function given in a `#[clone_with(path::to_fn)]` annotation on the field with references to
+/// the cloned fields it borrows, or by calling [`RebaseClone::rebase_clone()`] on the original
+/// value. A `#[clone_with()]` annotation on a **head field** names a function which is called with
+/// a reference to the field instead of `Clone::clone()`. `Default` can be derived in a similar way: fields which do not borrow anything are
+/// created with `Default::default()`, and fields which do are created from references to the
+/// fields they borrow by [`DefaultFrom::default_from()`]. A `#[default_with(path::to_fn)]`
+/// annotation on a field names a function to call instead, with the references as arguments.
+///
+/// With the `serde` feature enabled, `Serialize` and `Deserialize` can be derived as well.
+/// `Serialize` writes all **head fields** and any **self-referencing fields** annotated with
+/// `#[ouroboros(serialize)]`. `Deserialize` reads the **head fields** into `Heads` and passes them
+/// to `MyStructBuilder`, which requires every **self-referencing field** to have a
+/// `#[deserialize_with(path::to_fn)]` annotation naming the builder function for that field.
+/// Serialized **self-referencing fields** are ignored while deserializing since they are rebuilt.
+/// Fields annotated with `#[ouroboros(skip)]` are not serialized either, and skipped **head
+/// fields** are set to `Default::default()` when deserializing, like with `#[serde(skip)]`.
 pub use ouroboros_macro::self_referencing;
 
+/// Creates a copy of a self-referencing field which refers to the fields of a clone of the struct
+/// it is stored in. This is what `#[derive(Clone)]` uses for self-referencing fields which do not
+/// have a `#[clone_with()]` annotation. `Borrowed` is the reference to the cloned field for fields
+/// which borrow one field and a tuple of references for fields which borrow multiple fields. An
+/// example:
+/// ```rust
+/// use ouroboros::{self_referencing, RebaseClone};
+///
+/// #[derive(Clone)]
+/// struct Words<'a>(Vec<&'a str>);
+///
+/// impl<'old, 'new> RebaseClone<'new, &'new String> for Words<'old> {
+///     type Output = Words<'new>;
+///
+///     fn rebase_clone(&self, text: &'new String) -> Words<'new> {
+///         Words(text.split(' ').collect())
+///     }
+/// }
+///
+/// #[self_referencing]
+/// #[derive(Clone)]
+/// struct Text {
+///     text: String,
+///     #[borrows(text)]
+///     #[covariant]
+///     words: Words<'this>,
+/// }
+///
+/// fn main() {
+///     let text = TextBuilder {
+///         text: "Hello world!".to_owned(),
+///         words_builder: |text| Words(text.split(' ').collect()),
+///     }.build();
+///     let copy = text.clone();
+///     assert_eq!(copy.borrow_words().0, ["Hello", "world!"]);
+/// }
+/// ```
+pub trait RebaseClone<'new, Borrowed> {
+    /// The type of the copy, usually `Self` with its lifetime replaced by `'new`.
+    type Output;
+
+    /// Creates a copy of `self` which refers to `borrowed` instead of the fields that `self` was
+    /// created from.
+    fn rebase_clone(&self, borrowed: Borrowed) -> Self::Output;
+}
+
+/// A reference is assumed to point at the field it borrows, so the copy points at the cloned
+/// field. Use `#[clone_with()]` for references which point somewhere inside of the borrowed field.
+impl<'new, T: ?Sized> RebaseClone<'new, &'new T> for &T {
+    type Output = &'new T;
+
+    fn rebase_clone(&self, borrowed: &'new T) -> &'new T {
+        borrowed
+    }
+}
+
+/// Implemented for types which borrow data and are covariant over the lifetime of that borrow,
+/// written with `'static` in place of that lifetime. `Output` is the same type with `'static`
+/// replaced by `'a`. This is what [`Owned`] uses to store a value borrowing its cart. An
+/// implementation for a custom type looks like this:
+/// ```rust
+/// use ouroboros::Covariant;
+///
+/// struct Words<'a>(Vec<&'a str>);
+///
+/// unsafe impl<'a> Covariant<'a> for Words<'static> {
+///     type Output = Words<'a>;
+///
+///     fn shorten(&'a self) -> &'a Words<'a> {
+///         self
+///     }
+///
+///     fn shorten_owned(self) -> Words<'a> {
+///         self
+///     }
+///
+///     unsafe fn lengthen(from: Words<'a>) -> Self {
+///         core::mem::transmute(from)
+///     }
+/// }
+/// ```
+///
+/// # Safety
+/// `Output` must be `Self` with every occurrence of one lifetime replaced by `'a`, and `Self` must
+/// be covariant over that lifetime. The compiler checks the latter in `shorten` and
+/// `shorten_owned` when they are implemented by returning `self`, as above.
+pub unsafe trait Covariant<'a>: 'static {
+    /// `Self` with `'static` replaced by `'a`.
+    type Output: 'a;
+
+    /// Shortens the lifetime of a reference to `self`.
+    fn shorten(&'a self) -> &'a Self::Output;
+
+    /// Shortens the lifetime of `self`.
+    fn shorten_owned(self) -> Self::Output;
+
+    /// Extends the lifetime of `from` to `'static`.
+    ///
+    /// # Safety
+    /// The result must not be used after the data `from` borrows is gone.
+    unsafe fn lengthen(from: Self::Output) -> Self;
+}
+
+unsafe impl<'a, T: ?Sized + 'static> Covariant<'a> for &'static T {
+    type Output = &'a T;
+
+    fn shorten(&'a self) -> &'a &'a T {
+        self
+    }
+
+    fn shorten_owned(self) -> &'a T {
+        self
+    }
+
+    unsafe fn lengthen(from: &'a T) -> Self {
+        &*(from as *const T)
+    }
+}
+
+unsafe impl<'a, Y: Covariant<'a>> Covariant<'a> for Option<Y> {
+    type Output = Option<Y::Output>;
+
+    fn shorten(&'a self) -> &'a Option<Y::Output> {
+        // Y::Output is Y with a shorter lifetime, so both options have the same layout.
+        unsafe { &*(self as *const Self as *const Option<Y::Output>) }
+    }
+
+    fn shorten_owned(self) -> Option<Y::Output> {
+        self.map(Y::shorten_owned)
+    }
+
+    unsafe fn lengthen(from: Option<Y::Output>) -> Self {
+        from.map(|value| Y::lengthen(value))
+    }
+}
+
+unsafe impl<'a, Y: Covariant<'a>> Covariant<'a> for macro_help::alloc::vec::Vec<Y> {
+    type Output = macro_help::alloc::vec::Vec<Y::Output>;
+
+    fn shorten(&'a self) -> &'a Self::Output {
+        // Y::Output is Y with a shorter lifetime, so both vectors have the same layout.
+        unsafe { &*(self as *const Self as *const Self::Output) }
+    }
+
+    fn shorten_owned(self) -> Self::Output {
+        let mut vec = core::mem::ManuallyDrop::new(self);
+        let (ptr, len, capacity) = (vec.as_mut_ptr(), vec.len(), vec.capacity());
+        unsafe { macro_help::alloc::vec::Vec::from_raw_parts(ptr as *mut Y::Output, len, capacity) }
+    }
+
+    unsafe fn lengthen(from: Self::Output) -> Self {
+        let mut vec = core::mem::ManuallyDrop::new(from);
+        let (ptr, len, capacity) = (vec.as_mut_ptr(), vec.len(), vec.capacity());
+        macro_help::alloc::vec::Vec::from_raw_parts(ptr as *mut Y, len, capacity)
+    }
+}
+
+/// A self-referencing container made of a `Cart` and a value of type `Y` borrowing from it, for
+/// when writing a `#[self_referencing]` struct with one head field and one field borrowing it would
+/// be overkill. The cart is boxed so that it does not move along with the container. `Y` is written
+/// with `'static` in place of the lifetime of the borrow, see [`Covariant`]. An example:
+/// ```rust
+/// use ouroboros::Owned;
+///
+/// fn main() {
+///     let text: Owned<String, &'static str> =
+///         Owned::attach_to_cart("  Hello world!  ".to_owned(), |text| text.trim());
+///     assert_eq!(*text.get(), "Hello world!");
+///     let first_word: Owned<String, Option<&'static str>> =
+///         text.map_project(|trimmed, _| trimmed.split(' ').next());
+///     assert_eq!(*first_word.get(), Some("Hello"));
+///     assert_eq!(first_word.backing_cart(), "  Hello world!  ");
+/// }
+/// ```
+pub struct Owned<Cart, Y: for<'a> Covariant<'a>> {
+    // Dropped manually, before the cart it borrows.
+    value: core::mem::ManuallyDrop<Y>,
+    cart: macro_help::AliasableBox<Cart>,
+}
+
+/// Shortens the lifetime of `value` to the lifetime of the borrow of the cart it was created from.
+fn shorten_to_cart<'a, Cart, Y: Covariant<'a>>(_cart: &'a Cart, value: Y) -> Y::Output {
+    value.shorten_owned()
+}
+
+impl<Cart, Y: for<'a> Covariant<'a>> Owned<Cart, Y> {
+    /// Boxes `cart` and stores the value `attach` creates from a reference to it alongside it.
+    pub fn attach_to_cart(
+        cart: Cart,
+        attach: impl for<'a> FnOnce(&'a Cart) -> <Y as Covariant<'a>>::Output,
+    ) -> Self {
+        let cart = macro_help::aliasable_boxed(cart);
+        let value = unsafe { Y::lengthen(attach(&*cart)) };
+        Self {
+            value: core::mem::ManuallyDrop::new(value),
+            cart,
+        }
+    }
+
+    /// Like [`Owned::attach_to_cart`], but `attach` can fail. The error it returns is returned
+    /// as is and `cart` is dropped.
+    pub fn try_attach_to_cart<Error_>(
+        cart: Cart,
+        attach: impl for<'a> FnOnce(&'a Cart) -> Result<<Y as Covariant<'a>>::Output, Error_>,
+    ) -> Result<Self, Error_> {
+        let cart = macro_help::aliasable_boxed(cart);
+        let value = unsafe { Y::lengthen(attach(&*cart)?) };
+        Ok(Self {
+            value: core::mem::ManuallyDrop::new(value),
+            cart,
+        })
+    }
+
+    /// Returns a reference to the value borrowing the cart.
+    pub fn get<'a>(&'a self) -> &'a <Y as Covariant<'a>>::Output {
+        Y::shorten(&*self.value)
+    }
+
+    /// Returns a reference to the cart.
+    pub fn backing_cart(&self) -> &Cart {
+        &self.cart
+    }
+
+    /// Drops the value borrowing the cart and returns the cart.
+    pub fn into_backing_cart(self) -> Cart {
+        let (value, cart) = self.into_raw_parts();
+        drop(shorten_to_cart(&*cart, value));
+        macro_help::unbox(cart)
+    }
+
+    /// Creates a container which keeps the same cart, with a value computed by `project` from the
+    /// current value and a reference to the cart. The cart is not moved, so `project` may return
+    /// references to it.
+    pub fn map_project<P: for<'a> Covariant<'a>>(
+        self,
+        project: impl for<'a> FnOnce(
+            <Y as Covariant<'a>>::Output,
+            &'a Cart,
+        ) -> <P as Covariant<'a>>::Output,
+    ) -> Owned<Cart, P> {
+        let (value, cart) = self.into_raw_parts();
+        let value = unsafe { P::lengthen(project(shorten_to_cart(&*cart, value), &*cart)) };
+        Owned {
+            value: core::mem::ManuallyDrop::new(value),
+            cart,
+        }
+    }
+
+    /// Like [`Owned::map_project`], but `project` can fail. If it does, its error is returned and
+    /// the cart is dropped.
+    pub fn try_map_project<P: for<'a> Covariant<'a>, Error_>(
+        self,
+        project: impl for<'a> FnOnce(
+            <Y as Covariant<'a>>::Output,
+            &'a Cart,
+        ) -> Result<<P as Covariant<'a>>::Output, Error_>,
+    ) -> Result<Owned<Cart, P>, Error_> {
+        let (value, cart) = self.into_raw_parts();
+        let value = unsafe { P::lengthen(project(shorten_to_cart(&*cart, value), &*cart)?) };
+        Ok(Owned {
+            value: core::mem::ManuallyDrop::new(value),
+            cart,
+        })
+    }
+
+    /// Takes both fields out of `self` without dropping it. The value must be dropped or
+    /// shortened before the cart is dropped.
+    fn into_raw_parts(self) -> (Y, macro_help::AliasableBox<Cart>) {
+        let mut this = core::mem::ManuallyDrop::new(self);
+        unsafe {
+            (
+                core::mem::ManuallyDrop::take(&mut this.value),
+                core::ptr::read(&this.cart),
+            )
+        }
+    }
+}
+
+impl<Cart, Y: for<'a> Covariant<'a>> Drop for Owned<Cart, Y> {
+    fn drop(&mut self) {
+        let value = unsafe { core::mem::ManuallyDrop::take(&mut self.value) };
+        // Drop the value with the lifetime of the cart rather than 'static.
+        drop(shorten_to_cart(&*self.cart, value));
+    }
+}
+
+/// A boxed owner along with a reference to data it owns, like `OwningRef` from the `owning_ref`
+/// crate. The owner is stored in an aliasable box so that moving an `OwningRef` neither moves the
+/// owner nor invalidates the reference. An example:
+/// ```rust
+/// use ouroboros::OwningRef;
+///
+/// fn main() {
+///     let numbers = OwningRef::new(vec![1, 2, 3, 4]).map(|numbers| &numbers[1..3]);
+///     assert_eq!(*numbers, [2, 3]);
+///     assert_eq!(numbers.into_owner(), [1, 2, 3, 4]);
+/// }
+/// ```
+pub struct OwningRef<O, T: ?Sized> {
+    owner: macro_help::AliasableBox<O>,
+    reference: *const T,
+}
+
+impl<O> OwningRef<O, O> {
+    /// Boxes `owner` and creates a reference to it.
+    pub fn new(owner: O) -> Self {
+        let owner = macro_help::aliasable_boxed(owner);
+        let reference = &*owner as *const O;
+        Self { owner, reference }
+    }
+}
+
+impl<O, T: ?Sized> OwningRef<O, T> {
+    /// Replaces the reference with the one `map` returns when called with it.
+    pub fn map<U: ?Sized>(self, map: impl for<'a> FnOnce(&'a T) -> &'a U) -> OwningRef<O, U> {
+        let reference = map(unsafe { &*self.reference }) as *const U;
+        OwningRef {
+            owner: self.owner,
+            reference,
+        }
+    }
+
+    /// Like [`OwningRef::map`], but `map` can fail. If it does, its error is returned and the
+    /// owner is dropped.
+    pub fn try_map<U: ?Sized, Error_>(
+        self,
+        map: impl for<'a> FnOnce(&'a T) -> Result<&'a U, Error_>,
+    ) -> Result<OwningRef<O, U>, Error_> {
+        let reference = map(unsafe { &*self.reference })? as *const U;
+        Ok(OwningRef {
+            owner: self.owner,
+            reference,
+        })
+    }
+
+    /// Returns a reference to the owner.
+    pub fn as_owner(&self) -> &O {
+        &self.owner
+    }
+
+    /// Drops the reference and returns the owner.
+    pub fn into_owner(self) -> O {
+        macro_help::unbox(self.owner)
+    }
+}
+
+impl<O, T: ?Sized> core::ops::Deref for OwningRef<O, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { macro_help::change_lifetime(&*self.reference) }
+    }
+}
+
+unsafe impl<O: Send, T: ?Sized + Sync> Send for OwningRef<O, T> {}
+unsafe impl<O: Sync, T: ?Sized + Sync> Sync for OwningRef<O, T> {}
+
+/// Like [`OwningRef`], but the reference is mutable. There is no `as_owner()`, use
+/// `into_owning_ref()` or `into_owner()` instead. An example:
+/// ```rust
+/// use ouroboros::OwningRefMut;
+///
+/// fn main() {
+///     let mut last = OwningRefMut::new(vec![1, 2, 3]).map(|numbers| numbers.last_mut().unwrap());
+///     *last = 4;
+///     let last = last.into_owning_ref();
+///     assert_eq!(*last, 4);
+///     assert_eq!(last.as_owner(), &[1, 2, 4]);
+/// }
+/// ```
+pub struct OwningRefMut<O, T: ?Sized> {
+    owner: macro_help::AliasableBox<O>,
+    reference: *mut T,
+}
+
+impl<O> OwningRefMut<O, O> {
+    /// Boxes `owner` and creates a mutable reference to it.
+    pub fn new(owner: O) -> Self {
+        let mut owner = macro_help::aliasable_boxed(owner);
+        let reference = &mut *owner as *mut O;
+        Self { owner, reference }
+    }
+}
+
+impl<O, T: ?Sized> OwningRefMut<O, T> {
+    /// Replaces the reference with the one `map` returns when called with it.
+    pub fn map<U: ?Sized>(
+        self,
+        map: impl for<'a> FnOnce(&'a mut T) -> &'a mut U,
+    ) -> OwningRefMut<O, U> {
+        let reference = map(unsafe { &mut *self.reference }) as *mut U;
+        OwningRefMut {
+            owner: self.owner,
+            reference,
+        }
+    }
+
+    /// Like [`OwningRefMut::map`], but `map` can fail. If it does, its error is returned and the
+    /// owner is dropped.
+    pub fn try_map<U: ?Sized, Error_>(
+        self,
+        map: impl for<'a> FnOnce(&'a mut T) -> Result<&'a mut U, Error_>,
+    ) -> Result<OwningRefMut<O, U>, Error_> {
+        let reference = map(unsafe { &mut *self.reference })? as *mut U;
+        Ok(OwningRefMut {
+            owner: self.owner,
+            reference,
+        })
+    }
+
+    /// Turns the mutable reference into a shared one. This is needed to access the owner with
+    /// [`OwningRef::as_owner`], since reading the owner would invalidate a mutable reference to
+    /// data stored inside of it.
+    pub fn into_owning_ref(self) -> OwningRef<O, T> {
+        OwningRef {
+            owner: self.owner,
+            reference: self.reference,
+        }
+    }
+
+    /// Drops the reference and returns the owner.
+    pub fn into_owner(self) -> O {
+        macro_help::unbox(self.owner)
+    }
+}
+
+impl<O, T: ?Sized> core::ops::Deref for OwningRefMut<O, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { macro_help::change_lifetime(&*self.reference) }
+    }
+}
+
+impl<O, T: ?Sized> core::ops::DerefMut for OwningRefMut<O, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { macro_help::change_lifetime_mut(&mut *self.reference) }
+    }
+}
+
+unsafe impl<O: Send, T: ?Sized + Send> Send for OwningRefMut<O, T> {}
+unsafe impl<O: Sync, T: ?Sized + Sync> Sync for OwningRefMut<O, T> {}
+
+/// Creates the default value of a self-referencing field from the fields it borrows. This is what
+/// `#[derive(Default)]` uses for fields which borrow other fields and do not have a
+/// `#[default_with()]` annotation. `Borrowed` is the reference to the borrowed field for fields
+/// which borrow one field and a tuple of references for fields which borrow multiple fields. An
+/// example:
+/// ```rust
+/// use ouroboros::{self_referencing, DefaultFrom};
+///
+/// struct Words<'a>(Vec<&'a str>);
+///
+/// impl<'a> DefaultFrom<&'a String> for Words<'a> {
+///     fn default_from(text: &'a String) -> Words<'a> {
+///         Words(text.split(' ').collect())
+///     }
+/// }
+///
+/// #[self_referencing]
+/// #[derive(Default)]
+/// struct Text {
+///     text: String,
+///     #[borrows(text)]
+///     #[covariant]
+///     words: Words<'this>,
+/// }
+///
+/// fn main() {
+///     let text = Text::default();
+///     assert_eq!(text.borrow_words().0, [""]);
+/// }
+/// ```
+pub trait DefaultFrom<Borrowed> {
+    /// Creates the default value of the field from the fields it borrows.
+    fn default_from(borrowed: Borrowed) -> Self;
+}
+
+/// A reference defaults to the field it borrows.
+impl<'a, T: ?Sized> DefaultFrom<&'a T> for &'a T {
+    fn default_from(borrowed: &'a T) -> &'a T {
+        borrowed
+    }
+}
+
+/// A mutable reference defaults to the field it borrows.
+impl<'a, T: ?Sized> DefaultFrom<&'a mut T> for &'a mut T {
+    fn default_from(borrowed: &'a mut T) -> &'a mut T {
+        borrowed
+    }
+}
+
 #[doc(hidden)]
 pub mod macro_help {
     pub extern crate alloc;
 
     pub use aliasable::boxed::AliasableBox;
-    pub use static_assertions::assert_impl_all;
     use aliasable::boxed::UniqueBox;
+    #[cfg(feature = "allocator_api2")]
+    pub use allocator_api2;
+    use core::pin::Pin;
+    #[cfg(feature = "serde")]
+    pub use serde;
+    pub use static_assertions::assert_impl_all;
 
     pub struct CheckIfTypeIsStd<T>(core::marker::PhantomData<T>);
 
@@ -369,6 +1114,334 @@ pub mod macro_help {
     std_type_check!(is_std_arc_type T alloc::sync::Arc<T>);
     std_type_check!(is_std_rc_type T alloc::rc::Rc<T>);
 
+    impl<T> CheckIfTypeIsStd<alloc::vec::Vec<T>> {
+        pub fn is_std_vec_type() {}
+    }
+
+    impl CheckIfTypeIsStd<alloc::string::String> {
+        pub fn is_std_string_type() {}
+    }
+
+    /// Like `AliasableBox`, but for `Vec`. The one from `aliasable` turns itself back into a `Vec`
+    /// through a reference to the first element, which does not grant access to the others.
+    pub struct AliasableVec<T> {
+        ptr: core::ptr::NonNull<T>,
+        len: usize,
+        capacity: usize,
+    }
+
+    unsafe impl<T: Send> Send for AliasableVec<T> {}
+    unsafe impl<T: Sync> Sync for AliasableVec<T> {}
+
+    impl<T> AliasableVec<T> {
+        pub fn from_unique(vec: alloc::vec::Vec<T>) -> Self {
+            let mut vec = core::mem::ManuallyDrop::new(vec);
+            Self {
+                ptr: unsafe { core::ptr::NonNull::new_unchecked(vec.as_mut_ptr()) },
+                len: vec.len(),
+                capacity: vec.capacity(),
+            }
+        }
+
+        pub fn into_unique(vec: Self) -> alloc::vec::Vec<T> {
+            let vec = core::mem::ManuallyDrop::new(vec);
+            unsafe { alloc::vec::Vec::from_raw_parts(vec.ptr.as_ptr(), vec.len, vec.capacity) }
+        }
+    }
+
+    impl<T> core::ops::Deref for AliasableVec<T> {
+        type Target = [T];
+
+        fn deref(&self) -> &[T] {
+            unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+        }
+    }
+
+    impl<T> core::ops::DerefMut for AliasableVec<T> {
+        fn deref_mut(&mut self) -> &mut [T] {
+            unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+        }
+    }
+
+    impl<T> Drop for AliasableVec<T> {
+        fn drop(&mut self) {
+            drop(unsafe {
+                alloc::vec::Vec::from_raw_parts(self.ptr.as_ptr(), self.len, self.capacity)
+            });
+        }
+    }
+
+    /// Like `AliasableVec`, but for `String`.
+    pub struct AliasableString(AliasableVec<u8>);
+
+    impl AliasableString {
+        pub fn from_unique(string: alloc::string::String) -> Self {
+            Self(AliasableVec::from_unique(string.into_bytes()))
+        }
+
+        pub fn into_unique(string: Self) -> alloc::string::String {
+            let bytes = AliasableVec::into_unique(string.0);
+            unsafe { alloc::string::String::from_utf8_unchecked(bytes) }
+        }
+    }
+
+    impl core::ops::Deref for AliasableString {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            unsafe { core::str::from_utf8_unchecked(&self.0) }
+        }
+    }
+
+    impl core::ops::DerefMut for AliasableString {
+        fn deref_mut(&mut self) -> &mut str {
+            unsafe { core::str::from_utf8_unchecked_mut(&mut self.0) }
+        }
+    }
+
+    /// Implemented by the containers allowed in `#[stable_deref]` fields, whose contents do not
+    /// move when the container does. The container is stored as `Aliasable` so that moving the
+    /// struct does not assert unique access to the contents, which other fields refer to.
+    pub trait StableDeref: core::ops::Deref + Sized {
+        type Aliasable: core::ops::Deref<Target = Self::Target>;
+
+        fn into_aliasable(self) -> Self::Aliasable;
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self;
+    }
+
+    /// Implemented by `#[self_referencing]` structs made of a boxed head field followed by one field
+    /// borrowing it, so that the `map_FIELD()` methods of other structs can move their boxed head
+    /// into a new instance without allocating again. `Dependent` is the type of the second field
+    /// when `'this` is the lifetime of the trait.
+    ///
+    /// # Safety
+    ///
+    /// `assemble` must create the struct out of exactly the given head and dependent field.
+    pub unsafe trait MapTarget<'this> {
+        type Head;
+        type Dependent;
+
+        /// # Safety
+        ///
+        /// `dependent` must not borrow anything but the contents of `head`.
+        unsafe fn assemble(head: AliasableBox<Self::Head>, dependent: Self::Dependent) -> Self;
+    }
+
+    impl<T: ?Sized> StableDeref for alloc::boxed::Box<T> {
+        type Aliasable = AliasableBox<T>;
+
+        fn into_aliasable(self) -> Self::Aliasable {
+            AliasableBox::from_unique(self)
+        }
+
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self {
+            AliasableBox::into_unique(aliasable)
+        }
+    }
+
+    impl<T> StableDeref for alloc::vec::Vec<T> {
+        type Aliasable = AliasableVec<T>;
+
+        fn into_aliasable(self) -> Self::Aliasable {
+            AliasableVec::from_unique(self)
+        }
+
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self {
+            AliasableVec::into_unique(aliasable)
+        }
+    }
+
+    impl StableDeref for alloc::string::String {
+        type Aliasable = AliasableString;
+
+        fn into_aliasable(self) -> Self::Aliasable {
+            AliasableString::from_unique(self)
+        }
+
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self {
+            AliasableString::into_unique(aliasable)
+        }
+    }
+
+    // Shared pointers never assert unique access to their contents, so they can be stored as-is.
+    #[cfg(target_has_atomic = "ptr")]
+    impl<T: ?Sized> StableDeref for alloc::sync::Arc<T> {
+        type Aliasable = Self;
+
+        fn into_aliasable(self) -> Self::Aliasable {
+            self
+        }
+
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self {
+            aliasable
+        }
+    }
+
+    impl<T: ?Sized> StableDeref for alloc::rc::Rc<T> {
+        type Aliasable = Self;
+
+        fn into_aliasable(self) -> Self::Aliasable {
+            self
+        }
+
+        fn from_aliasable(aliasable: Self::Aliasable) -> Self {
+            aliasable
+        }
+    }
+
+    /// Formats a value with the function given in a `#[debug(with = path::to_fn)]` annotation.
+    pub struct DebugWith<'a, T: ?Sized, F>(&'a T, F);
+
+    impl<'a, T: ?Sized, F> core::fmt::Debug for DebugWith<'a, T, F>
+    where
+        F: Fn(&T, &mut core::fmt::Formatter<'_>) -> core::fmt::Result,
+    {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            (self.1)(self.0, f)
+        }
+    }
+
+    pub fn debug_with<T: ?Sized, F>(value: &T, format: F) -> DebugWith<'_, T, F>
+    where
+        F: Fn(&T, &mut core::fmt::Formatter<'_>) -> core::fmt::Result,
+    {
+        DebugWith(value, format)
+    }
+
+    /// Memory for a pinned self-referencing struct or a block of borrowed fields which is
+    /// initialized one field at a time. If it is dropped before being turned into a `Pin<Box<T>>`
+    /// or an `AliasableBlock<T>`, the memory is freed without dropping anything stored in it.
+    pub struct UninitBox<T>(core::ptr::NonNull<T>);
+
+    // Only ever accessed through a pointer to the initialized parts of `T`.
+    unsafe impl<T: Send> Send for UninitBox<T> {}
+    unsafe impl<T: Sync> Sync for UninitBox<T> {}
+
+    impl<T> UninitBox<T> {
+        #[allow(clippy::new_without_default)]
+        pub fn new() -> Self {
+            let memory = alloc::boxed::Box::new(core::mem::MaybeUninit::<T>::uninit());
+            Self(core::ptr::NonNull::from(alloc::boxed::Box::leak(memory)).cast())
+        }
+
+        /// Takes ownership of the memory of a pinned box, leaving it to the caller to drop or move
+        /// out its contents.
+        ///
+        /// # Safety
+        ///
+        /// The caller must uphold the guarantees of `Pin` for the contents of the box.
+        pub unsafe fn from_pinned(pinned: Pin<alloc::boxed::Box<T>>) -> Self {
+            let memory = alloc::boxed::Box::into_raw(Pin::into_inner_unchecked(pinned));
+            Self(core::ptr::NonNull::new_unchecked(memory))
+        }
+
+        pub fn as_ptr(&self) -> *mut T {
+            self.0.as_ptr()
+        }
+
+        /// # Safety
+        ///
+        /// The caller must ensure that the memory holds a valid instance of `T`.
+        pub unsafe fn assume_init(self) -> Pin<alloc::boxed::Box<T>> {
+            let memory = self.0.as_ptr();
+            core::mem::forget(self);
+            Pin::new_unchecked(alloc::boxed::Box::from_raw(memory))
+        }
+
+        /// # Safety
+        ///
+        /// The caller must ensure that the memory holds a valid instance of `T`.
+        pub unsafe fn assume_init_block(self) -> AliasableBlock<T> {
+            let memory = self.0;
+            core::mem::forget(self);
+            AliasableBlock(memory)
+        }
+    }
+
+    impl<T> Drop for UninitBox<T> {
+        fn drop(&mut self) {
+            let memory = self.0.as_ptr().cast::<core::mem::MaybeUninit<T>>();
+            drop(unsafe { alloc::boxed::Box::from_raw(memory) });
+        }
+    }
+
+    /// Drops the fields which have already been stored in an `UninitBox` or the slot of
+    /// `new_in_place()` if a builder panics or the future constructing the struct is dropped, in
+    /// the reverse of the order they were stored in. It is disarmed once every field is stored.
+    pub struct DropGuard<T> {
+        memory: core::ptr::NonNull<T>,
+        stored: usize,
+        drop_stored: unsafe fn(*mut T, usize),
+    }
+
+    // Only ever accessed through a pointer to the initialized parts of `T`.
+    unsafe impl<T: Send> Send for DropGuard<T> {}
+    unsafe impl<T: Sync> Sync for DropGuard<T> {}
+
+    impl<T> DropGuard<T> {
+        /// # Safety
+        ///
+        /// `memory` must stay valid while the guard is armed, and `drop_stored(memory, n)` must
+        /// drop exactly the first `n` fields stored in it.
+        pub unsafe fn new(memory: *mut T, drop_stored: unsafe fn(*mut T, usize)) -> Self {
+            Self {
+                memory: core::ptr::NonNull::new_unchecked(memory),
+                stored: 0,
+                drop_stored,
+            }
+        }
+
+        /// Records that the next field has been stored.
+        pub fn push(&mut self) {
+            self.stored += 1;
+        }
+
+        pub fn disarm(self) {
+            core::mem::forget(self);
+        }
+    }
+
+    impl<T> Drop for DropGuard<T> {
+        fn drop(&mut self) {
+            unsafe { (self.drop_stored)(self.memory.as_ptr(), self.stored) }
+        }
+    }
+
+    /// The allocation shared by all borrowed fields of a `#[self_referencing(single_allocation)]`
+    /// struct. Like `AliasableBox`, it never asserts unique access to its contents, so references
+    /// into it held by other fields stay valid while it is moved around.
+    pub struct AliasableBlock<T>(core::ptr::NonNull<T>);
+
+    unsafe impl<T: Send> Send for AliasableBlock<T> {}
+    unsafe impl<T: Sync> Sync for AliasableBlock<T> {}
+
+    impl<T> AliasableBlock<T> {
+        pub fn as_ptr(&self) -> *mut T {
+            self.0.as_ptr()
+        }
+
+        /// Gives up ownership of the contents, leaving it to the caller to drop or move them out.
+        pub fn into_uninit(self) -> UninitBox<T> {
+            let memory = self.0;
+            core::mem::forget(self);
+            UninitBox(memory)
+        }
+    }
+
+    impl<T> core::ops::Deref for AliasableBlock<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { self.0.as_ref() }
+        }
+    }
+
+    impl<T> Drop for AliasableBlock<T> {
+        fn drop(&mut self) {
+            drop(unsafe { alloc::boxed::Box::from_raw(self.0.as_ptr()) });
+        }
+    }
+
     pub fn aliasable_boxed<T>(data: T) -> AliasableBox<T> {
         AliasableBox::from_unique(UniqueBox::new(data))
     }
@@ -377,6 +1450,175 @@ pub mod macro_help {
         *AliasableBox::into_unique(boxed)
     }
 
+    /// Like `AliasableBox`, but allocated with `A` instead of the global allocator. Used for the
+    /// borrowed fields of `#[self_referencing(allocator = A)]` structs.
+    #[cfg(feature = "allocator_api2")]
+    pub struct AliasableBoxIn<T: ?Sized, A: allocator_api2::alloc::Allocator> {
+        ptr: core::ptr::NonNull<T>,
+        allocator: core::mem::ManuallyDrop<A>,
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    unsafe impl<T: ?Sized + Send, A: allocator_api2::alloc::Allocator + Send> Send
+        for AliasableBoxIn<T, A>
+    {
+    }
+    #[cfg(feature = "allocator_api2")]
+    unsafe impl<T: ?Sized + Sync, A: allocator_api2::alloc::Allocator + Sync> Sync
+        for AliasableBoxIn<T, A>
+    {
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    impl<T: ?Sized, A: allocator_api2::alloc::Allocator> AliasableBoxIn<T, A> {
+        pub fn from_unique(boxed: allocator_api2::boxed::Box<T, A>) -> Self {
+            let (ptr, allocator) = allocator_api2::boxed::Box::into_raw_with_allocator(boxed);
+            Self {
+                ptr: unsafe { core::ptr::NonNull::new_unchecked(ptr) },
+                allocator: core::mem::ManuallyDrop::new(allocator),
+            }
+        }
+
+        pub fn into_unique(boxed: Self) -> allocator_api2::boxed::Box<T, A> {
+            let mut boxed = core::mem::ManuallyDrop::new(boxed);
+            unsafe {
+                let allocator = core::mem::ManuallyDrop::take(&mut boxed.allocator);
+                allocator_api2::boxed::Box::from_raw_in(boxed.ptr.as_ptr(), allocator)
+            }
+        }
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    impl<T: ?Sized, A: allocator_api2::alloc::Allocator> core::ops::Deref for AliasableBoxIn<T, A> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { self.ptr.as_ref() }
+        }
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    impl<T: ?Sized, A: allocator_api2::alloc::Allocator> core::ops::DerefMut for AliasableBoxIn<T, A> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { self.ptr.as_mut() }
+        }
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    impl<T: ?Sized, A: allocator_api2::alloc::Allocator> Drop for AliasableBoxIn<T, A> {
+        fn drop(&mut self) {
+            drop(unsafe {
+                let allocator = core::mem::ManuallyDrop::take(&mut self.allocator);
+                allocator_api2::boxed::Box::from_raw_in(self.ptr.as_ptr(), allocator)
+            });
+        }
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    pub fn aliasable_boxed_in<T, A: allocator_api2::alloc::Allocator>(
+        data: T,
+        allocator: A,
+    ) -> AliasableBoxIn<T, A> {
+        AliasableBoxIn::from_unique(allocator_api2::boxed::Box::new_in(data, allocator))
+    }
+
+    #[cfg(feature = "allocator_api2")]
+    pub fn unbox_in<T, A: allocator_api2::alloc::Allocator>(boxed: AliasableBoxIn<T, A>) -> T {
+        allocator_api2::boxed::Box::into_inner(AliasableBoxIn::into_unique(boxed))
+    }
+
+    /// Turns a panic into an abort while it is alive, for code which leaves a struct in an invalid
+    /// state that must not be observed by its destructor. Forget it once the struct is valid again.
+    pub struct AbortOnUnwind;
+
+    impl Drop for AbortOnUnwind {
+        fn drop(&mut self) {
+            // Panicking while already unwinding aborts the process.
+            panic!("Panicked while a self-referencing struct was being rebuilt.");
+        }
+    }
+
+    /// A future which polls two futures until both have completed and outputs both results. Used
+    /// by `build_concurrent()` to await builders which do not depend on each other. The futures
+    /// of `async_fn` builders are not boxed, so `a` and `b` are structurally pinned.
+    pub struct Join<A: core::future::Future, B: core::future::Future> {
+        a: A,
+        b: B,
+        a_output: Option<A::Output>,
+        b_output: Option<B::Output>,
+    }
+
+    pub fn join<A: core::future::Future, B: core::future::Future>(a: A, b: B) -> Join<A, B> {
+        Join {
+            a,
+            b,
+            a_output: None,
+            b_output: None,
+        }
+    }
+
+    impl<A: core::future::Future, B: core::future::Future> core::future::Future for Join<A, B> {
+        type Output = (A::Output, B::Output);
+
+        fn poll(
+            self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<Self::Output> {
+            // The futures are never moved out of `self`, only the outputs are, which are not pinned.
+            let this = unsafe { self.get_unchecked_mut() };
+            if this.a_output.is_none() {
+                let a = unsafe { Pin::new_unchecked(&mut this.a) };
+                if let core::task::Poll::Ready(output) = a.poll(cx) {
+                    this.a_output = Some(output);
+                }
+            }
+            if this.b_output.is_none() {
+                let b = unsafe { Pin::new_unchecked(&mut this.b) };
+                if let core::task::Poll::Ready(output) = b.poll(cx) {
+                    this.b_output = Some(output);
+                }
+            }
+            match (this.a_output.take(), this.b_output.take()) {
+                (Some(a), Some(b)) => core::task::Poll::Ready((a, b)),
+                (a, b) => {
+                    this.a_output = a;
+                    this.b_output = b;
+                    core::task::Poll::Pending
+                }
+            }
+        }
+    }
+
+    /// Holds the head fields of a struct being built by `build_cancellable()`. If it is dropped
+    /// before `disarm()` is called, because the future building the struct was dropped, the heads
+    /// are passed to `on_cancel`.
+    pub struct CancelGuard<T, F: FnOnce(T)> {
+        value: Option<T>,
+        on_cancel: Option<F>,
+    }
+
+    impl<T, F: FnOnce(T)> CancelGuard<T, F> {
+        pub fn new(value: T, on_cancel: F) -> Self {
+            Self {
+                value: Some(value),
+                on_cancel: Some(on_cancel),
+            }
+        }
+
+        pub fn disarm(mut self) -> T {
+            self.on_cancel = None;
+            self.value.take().unwrap()
+        }
+    }
+
+    impl<T, F: FnOnce(T)> Drop for CancelGuard<T, F> {
+        fn drop(&mut self) {
+            if let (Some(value), Some(on_cancel)) = (self.value.take(), self.on_cancel.take()) {
+                on_cancel(value);
+            }
+        }
+    }
+
     /// Converts a reference to an object to a static reference This is
     /// obviously unsafe because the compiler can no longer guarantee that the
     /// data outlives the reference.  It is up to the consumer to get rid of the
@@ -388,7 +1630,7 @@ pub mod macro_help {
     ///
     /// The caller must ensure that the returned reference is not used after the originally passed
     /// reference would become invalid.
-    pub unsafe fn change_lifetime<'old, 'new: 'old, T: 'new>(data: &'old T) -> &'new T {
+    pub unsafe fn change_lifetime<'old, 'new: 'old, T: ?Sized + 'new>(data: &'old T) -> &'new T {
         &*(data as *const _)
     }
 
@@ -398,7 +1640,9 @@ pub mod macro_help {
     ///
     /// The caller must ensure that the returned reference is not used after the originally passed
     /// reference would become invalid.
-    pub unsafe fn change_lifetime_mut<'old, 'new: 'old, T: 'new>(data: &'old mut T) -> &'new mut T {
+    pub unsafe fn change_lifetime_mut<'old, 'new: 'old, T: ?Sized + 'new>(
+        data: &'old mut T,
+    ) -> &'new mut T {
         &mut *(data as *mut _)
     }
 }
@@ -1,9 +1,22 @@
 use quote::ToTokens;
 use syn::{GenericArgument, PathArguments, Type};
 
-use crate::utils::uses_this_lifetime;
+use crate::{info_structures::StableDerefType, utils::uses_this_lifetime};
 
 const STD_CONTAINER_TYPES: &[&str] = &["Box", "Arc", "Rc"];
+const STD_BORROWING_ITERATOR_TYPES: &[&str] = &[
+    "Iter",
+    "IterMut",
+    "Keys",
+    "Values",
+    "ValuesMut",
+    "Chunks",
+    "ChunksExact",
+    "Windows",
+    "Lines",
+    "Split",
+    "SplitWhitespace",
+];
 
 /// Returns Some((type_name, element_type)) if the provided type appears to be Box, Arc, or Rc from
 /// the standard library. Returns None if not.
@@ -36,6 +49,64 @@ pub fn apparent_std_container_type(raw_type: &Type) -> Option<(&'static str, &Ty
     None
 }
 
+/// Returns Some((container, element_type)) if the provided type appears to be one of the standard
+/// library containers which can be used in `#[stable_deref]` fields. The element type is None for
+/// String.
+pub fn apparent_stable_deref_type(raw_type: &Type) -> Option<(StableDerefType, Option<&Type>)> {
+    if let Some((type_name, eltype)) = apparent_std_container_type(raw_type) {
+        let container = match type_name {
+            "Box" => StableDerefType::Box,
+            "Arc" => StableDerefType::Arc,
+            _ => StableDerefType::Rc,
+        };
+        return Some((container, Some(eltype)));
+    }
+    let tpath = if let Type::Path(x) = raw_type {
+        x
+    } else {
+        return None;
+    };
+    let segment = tpath.path.segments.last()?;
+    match &segment.arguments {
+        PathArguments::None if segment.ident == "String" => Some((StableDerefType::String, None)),
+        PathArguments::AngleBracketed(args) if segment.ident == "Vec" && args.args.len() == 1 => {
+            if let GenericArgument::Type(eltype) = args.args.first().unwrap() {
+                Some((StableDerefType::Vec, Some(eltype)))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns true if the provided type appears to be an iterator from the standard library which
+/// yields references to data it borrows for the 'this lifetime, like `slice::Iter<'this, T>`.
+pub fn apparent_std_borrowing_iterator_type(raw_type: &Type) -> bool {
+    let tpath = if let Type::Path(x) = raw_type {
+        x
+    } else {
+        return false;
+    };
+    let segment = if let Some(segment) = tpath.path.segments.last() {
+        segment
+    } else {
+        return false;
+    };
+    let args = if let PathArguments::AngleBracketed(args) = &segment.arguments {
+        args
+    } else {
+        return false;
+    };
+    STD_BORROWING_ITERATOR_TYPES
+        .iter()
+        .any(|type_name| segment.ident == type_name)
+        && args.args.iter().any(|arg| match arg {
+            GenericArgument::Lifetime(lifetime) => lifetime.ident == "this",
+            _ => false,
+        })
+}
+
 /// Returns Some(true or false) if the type is known to be covariant / not covariant.
 pub fn type_is_covariant_over_this_lifetime(ty: &syn::Type) -> Option<bool> {
     use syn::Type::*;
@@ -0,0 +1,339 @@
+use heck::ToSnakeCase;
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    token::Semi, Attribute, Error, Fields, GenericParam, Ident, ItemEnum, ItemStruct, Lifetime,
+    Variant, WhereClause,
+};
+
+use crate::{
+    info_structures::Options,
+    self_referencing_impl,
+    utils::{make_generic_arguments, submodule_contents_visibility},
+};
+
+fn is_self_referencing(variant: &Variant) -> bool {
+    variant.fields.iter().any(|field| {
+        field
+            .attrs
+            .iter()
+            .any(|attr| attr.path().is_ident("borrows"))
+    })
+}
+
+fn variant_struct_ident(def: &ItemEnum, variant: &Variant) -> Ident {
+    format_ident!("{}{}", def.ident, variant.ident)
+}
+
+/// Turns a variant which contains self-referencing fields into a standalone struct definition.
+/// The struct is then run through the same code generation as any other self-referencing struct.
+fn make_variant_struct_def(def: &ItemEnum, variant: &Variant) -> ItemStruct {
+    let mut attrs: Vec<Attribute> = variant
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .cloned()
+        .collect();
+    // The enum can only implement a derived trait if every variant does.
+    attrs.extend(
+        def.attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("derive"))
+            .cloned(),
+    );
+    let mut fields = variant.fields.clone();
+    // Fields of an enum variant are as visible as the enum itself.
+    for field in fields.iter_mut() {
+        field.vis = def.vis.clone();
+    }
+    let semi_token = if let Fields::Named(..) = fields {
+        None
+    } else {
+        Some(Semi::default())
+    };
+    ItemStruct {
+        attrs,
+        vis: def.vis.clone(),
+        struct_token: Default::default(),
+        ident: variant_struct_ident(def, variant),
+        generics: def.generics.clone(),
+        fields,
+        semi_token,
+    }
+}
+
+/// Generates a pattern which matches the given variant of an enum, binding all its fields.
+fn variant_pattern(variant: &Variant, bindings: &[Ident]) -> TokenStream {
+    let ident = &variant.ident;
+    match &variant.fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            quote! { #ident { #(#names: #bindings),* } }
+        }
+        Fields::Unnamed(..) => quote! { #ident ( #(#bindings),* ) },
+        Fields::Unit => quote! { #ident },
+    }
+}
+
+fn variant_bindings(variant: &Variant) -> Vec<Ident> {
+    (0..variant.fields.len())
+        .map(|index| format_ident!("field_{}", index))
+        .collect()
+}
+
+pub fn self_referencing_enum_impl(
+    original_enum_def: &ItemEnum,
+    options: &Options,
+) -> Result<TokenStream, Error> {
+    let enum_name = &original_enum_def.ident;
+    let mod_name = format_ident!("ouroboros_impl_{}", enum_name.to_string().to_snake_case());
+    let visibility = &original_enum_def.vis;
+    let variant_ref_name = format_ident!("{}Variant", enum_name);
+    if options.do_pinned {
+        return Err(Error::new(
+            Span::call_site(),
+            "Self-referencing enums cannot be pinned, as they hold their variants by value.",
+        ));
+    }
+    if !original_enum_def.variants.iter().any(is_self_referencing) {
+        return Err(Error::new(
+            Span::call_site(),
+            concat!(
+                "Self-referencing enums must have at least one variant containing a field with ",
+                "a #[borrows()] annotation."
+            ),
+        ));
+    }
+
+    if let Some(variant) = original_enum_def
+        .variants
+        .iter()
+        .find(|variant| is_self_referencing(variant) && variant.ident == "Variant")
+    {
+        return Err(Error::new_spanned(
+            variant,
+            format!(
+                concat!(
+                    "A self-referencing variant cannot be named `Variant`, as the struct ",
+                    "generated for it would have the same name as `{0}Variant`, which holds ",
+                    "the fields passed to `{0}::with_variant()`."
+                ),
+                enum_name
+            ),
+        ));
+    }
+
+    let generics = &original_enum_def.generics;
+    let generic_params = &generics.params;
+    let generic_args = make_generic_arguments(generic_params.iter().collect());
+    let generic_where = &generics.where_clause;
+    let extra_visibility = if options.do_pub_extras {
+        visibility.clone()
+    } else {
+        syn::Visibility::Inherited
+    };
+    let member_visibility = submodule_contents_visibility(visibility);
+    // The variant enum holds the BorrowedFields of the variant structs, so it and with_variant()
+    // cannot be more visible than they are.
+    let extra_member_visibility = submodule_contents_visibility(&extra_visibility);
+    let fake_lifetime = if let Some(GenericParam::Lifetime(param)) = generic_params.first() {
+        param.lifetime.clone()
+    } else {
+        Lifetime::new("'static", Span::call_site())
+    };
+
+    let mut variant_struct_impls = Vec::new();
+    let mut variant_impls = Vec::new();
+    let mut variants = Vec::new();
+    let mut ref_variants = Vec::new();
+    let mut with_arms = Vec::new();
+    let mut accessors = Vec::new();
+    for variant in &original_enum_def.variants {
+        let variant_name = &variant.ident;
+        let snake_name = variant_name.to_string().to_snake_case();
+        let bindings = variant_bindings(variant);
+        let pattern = variant_pattern(variant, &bindings);
+        let is_name = format_ident!("is_{}", snake_name);
+        let as_name = format_ident!("as_{}", snake_name);
+        let is_documentation = format!(
+            "Returns `true` if this is a [`{0}`](Self::{0}).",
+            variant_name
+        );
+        let as_documentation = format!(
+            "Returns the contents of this enum if it is a [`{0}`](Self::{0}).",
+            variant_name
+        );
+        let (is_documentation, as_documentation) = if !options.do_no_doc {
+            (
+                quote! { #[doc=#is_documentation] },
+                quote! { #[doc=#as_documentation] },
+            )
+        } else {
+            (quote! { #[doc(hidden)] }, quote! { #[doc(hidden)] })
+        };
+        if is_self_referencing(variant) {
+            let struct_def = make_variant_struct_def(original_enum_def, variant);
+            let struct_name = &struct_def.ident;
+            let struct_mod_name =
+                format_ident!("ouroboros_impl_{}", struct_name.to_string().to_snake_case());
+            variant_struct_impls.push(TokenStream::from(self_referencing_impl(
+                &struct_def,
+                options,
+            )?));
+            let attrs = variant
+                .attrs
+                .iter()
+                .filter(|attr| !attr.path().is_ident("doc"));
+            let documentation = format!(
+                "See [`{0}`]({0}) for how to construct and access this variant.",
+                struct_name
+            );
+            variants.push(quote! {
+                #(#attrs)*
+                #[doc=#documentation]
+                #variant_name(#struct_name<#(#generic_args),*>)
+            });
+            let borrowed_generic_args = quote! { 'outer_borrow, 'this, #(#generic_args),* };
+            ref_variants.push(quote! {
+                #variant_name(super::#struct_mod_name::BorrowedFields<#borrowed_generic_args>)
+            });
+            with_arms.push(quote! {
+                Self::#variant_name(inner) => inner.with(|fields| user(#variant_ref_name::#variant_name(fields)))
+            });
+            accessors.push(quote! {
+                #is_documentation
+                #member_visibility fn #is_name(&self) -> bool {
+                    matches!(self, Self::#variant_name(..))
+                }
+                #as_documentation
+                #member_visibility fn #as_name(&self) -> ::core::option::Option<&#struct_name<#(#generic_args),*>> {
+                    #[allow(unreachable_patterns)]
+                    match self {
+                        Self::#variant_name(inner) => ::core::option::Option::Some(inner),
+                        _ => ::core::option::Option::None,
+                    }
+                }
+            });
+            variant_impls.push(quote! {
+                impl <#generic_params> ::core::convert::From<#struct_name<#(#generic_args),*>>
+                    for #enum_name<#(#generic_args),*> #generic_where
+                {
+                    fn from(inner: #struct_name<#(#generic_args),*>) -> Self {
+                        Self::#variant_name(inner)
+                    }
+                }
+            });
+        } else {
+            variants.push(quote! { #variant });
+            let field_types: Vec<_> = variant.fields.iter().map(|field| &field.ty).collect();
+            let ref_fields = match &variant.fields {
+                Fields::Named(fields) => {
+                    let names = fields.named.iter().map(|field| &field.ident);
+                    quote! { { #(#names: &'outer_borrow #field_types),* } }
+                }
+                Fields::Unnamed(..) => quote! { ( #(&'outer_borrow #field_types),* ) },
+                Fields::Unit => quote! {},
+            };
+            ref_variants.push(quote! { #variant_name #ref_fields });
+            with_arms.push(quote! {
+                Self::#pattern => user(#variant_ref_name::#pattern)
+            });
+            accessors.push(quote! {
+                #is_documentation
+                #member_visibility fn #is_name(&self) -> bool {
+                    matches!(self, Self::#variant_name { .. })
+                }
+            });
+            if field_types.len() == 1 {
+                accessors.push(quote! {
+                    #as_documentation
+                    #member_visibility fn #as_name(&self) -> ::core::option::Option<#(&#field_types)*> {
+                        #[allow(unreachable_patterns)]
+                        match self {
+                            Self::#pattern => ::core::option::Option::Some(#(#bindings)*),
+                            _ => ::core::option::Option::None,
+                        }
+                    }
+                });
+            } else if field_types.len() > 1 {
+                accessors.push(quote! {
+                    #as_documentation
+                    #member_visibility fn #as_name(&self) -> ::core::option::Option<(#(&#field_types),*)> {
+                        #[allow(unreachable_patterns)]
+                        match self {
+                            Self::#pattern => ::core::option::Option::Some((#(#bindings),*)),
+                            _ => ::core::option::Option::None,
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    let mut ref_where: WhereClause = match generic_where {
+        Some(clause) => clause.clone(),
+        None => syn::parse_quote! { where },
+    };
+    ref_where
+        .predicates
+        .push(syn::parse_quote! { #fake_lifetime: 'this });
+    ref_where
+        .predicates
+        .push(syn::parse_quote! { 'this: 'outer_borrow });
+    let ref_documentation = format!(
+        concat!(
+            "Holds immutable references to the fields of whichever variant an instance of ",
+            "[`{0}`]({0}) is. Variants containing self-referencing fields hold the ",
+            "`BorrowedFields` of their struct. Created by [`{0}::with_variant()`]. This enum ",
+            "is only visible to the module [`{0}`]({0}) is declared in unless ",
+            "`#[self_referencing(pub_extras)]` is used, as the `BorrowedFields` it holds are ",
+            "not visible outside of it either."
+        ),
+        enum_name
+    );
+    let with_documentation = concat!(
+        "Provides immutable references to the fields of the current variant, similarly to ",
+        "calling `with()` on the struct holding that variant. This is meant to be used with a ",
+        "`match` inside of `user`. This method is only visible to the module the enum is ",
+        "declared in unless `#[self_referencing(pub_extras)]` is used."
+    );
+    let with_documentation = if !options.do_no_doc {
+        quote! { #[doc=#with_documentation] }
+    } else {
+        quote! { #[doc(hidden)] }
+    };
+    let attrs = &original_enum_def.attrs;
+    let enum_visibility = submodule_contents_visibility(visibility);
+    let ref_generic_params = quote! { 'outer_borrow, 'this, #generic_params };
+    Ok(quote! {
+        #[doc="Encapsulates implementation details for a self-referencing enum. This module is only visible when using --document-private-items."]
+        mod #mod_name {
+            use super::*;
+            #(#attrs)*
+            #enum_visibility enum #enum_name <#generic_params> #generic_where {
+                #(#variants),*
+            }
+            #[doc=#ref_documentation]
+            #extra_member_visibility enum #variant_ref_name <#ref_generic_params> #ref_where {
+                #(#ref_variants),*
+            }
+            impl <#generic_params> #enum_name <#(#generic_args),*> #generic_where {
+                #(#accessors)*
+                #with_documentation
+                #[inline(always)]
+                #extra_member_visibility fn with_variant <'outer_borrow, ReturnType>(
+                    &'outer_borrow self,
+                    user: impl for<'this> ::core::ops::FnOnce(#variant_ref_name<'outer_borrow, 'this, #(#generic_args),*>) -> ReturnType
+                ) -> ReturnType {
+                    match self {
+                        #(#with_arms),*
+                    }
+                }
+            }
+            #(#variant_impls)*
+        }
+        #visibility use #mod_name :: #enum_name;
+        #extra_visibility use #mod_name :: #variant_ref_name;
+        #(#variant_struct_impls)*
+    })
+}
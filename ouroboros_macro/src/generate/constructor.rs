@@ -1,6 +1,5 @@
-use crate::{
-    info_structures::{ArgType, BuilderType, FieldType, Options, StructInfo},
-    utils::to_class_case,
+use crate::info_structures::{
+    ArgType, BuilderType, FieldStorage, FieldType, Options, StructFieldInfo, StructInfo,
 };
 use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
@@ -8,7 +7,7 @@ use syn::Error;
 
 pub fn create_builder_and_constructor(
     info: &StructInfo,
-    options: Options,
+    options: &Options,
     builder_type: BuilderType,
 ) -> Result<(Ident, TokenStream, TokenStream), Error> {
     let struct_name = info.ident.clone();
@@ -61,73 +60,165 @@ pub fn create_builder_and_constructor(
     let mut builder_struct_generic_consumers = info.generic_arguments();
     let mut builder_struct_fields = Vec::new();
     let mut builder_struct_field_names = Vec::new();
+    let is_tuple = info.is_tuple();
+    let internal_ident = &info.internal_ident;
+    let this_ptr = if builder_type.is_async() {
+        // Raw pointers are not Send, so they cannot be held across await points.
+        quote! { slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>() }
+    } else {
+        quote! { this }
+    };
 
     // code.push(quote! { let mut result = ::core::mem::MaybeUninit::<Self>::uninit(); });
+    // The call to the builder of each field, if it has one, and the code storing its value.
+    let mut field_steps = Vec::new();
+
+    // The calls to the builders of consumed fields, which are made right before the builder of
+    // the field consuming them.
+    let mut consumed_builder_calls = Vec::new();
 
     for field in &info.fields {
         let field_name = &field.name;
 
-        let arg_type = field.make_constructor_arg_type(info, builder_type)?;
-        if let ArgType::Plain(plain_type) = arg_type {
-            // No fancy builder function, we can just move the value directly into the struct.
-            params.push(quote! { #field_name: #plain_type });
-            builder_struct_fields.push(quote! { #field_name: #plain_type });
-            builder_struct_field_names.push(quote! { #field_name });
-            doc_table += &format!(
-                "| `{}` | Directly pass in the value this field should contain |\n",
-                field_name
-            );
-        } else if let ArgType::TraitBound(bound_type) = arg_type {
-            // Trait bounds are much trickier. We need a special syntax to accept them in the
-            // constructor, and generic parameters need to be added to the builder struct to make
-            // it work.
-            let builder_name = field.builder_name();
-            params.push(quote! { #builder_name : impl #bound_type });
-            doc_table += &format!(
-                "| `{}` | Use a function or closure: `(",
-                builder_name
-            );
-            let mut builder_args = Vec::new();
-            for (index, borrow) in field.borrows.iter().enumerate() {
-                let borrowed_name = &info.fields[borrow.index].name;
-                builder_args.push(format_ident!("{}_illegal_static_reference", borrowed_name));
+        let mut builder_call = None;
+        // Consumed fields are not stored, but constructors still take them in the order they
+        // were declared in.
+        for param_field in info.consumed_before(field).into_iter().chain(Some(field)) {
+            let param_name = &param_field.name;
+            let moved_into = if param_field.declaration_index == field.declaration_index {
+                String::new()
+            } else {
+                format!(
+                    ", which is moved into the builder of `{}`",
+                    info.consumer_of(param_field).accessor_name()
+                )
+            };
+            let arg_type = param_field.make_constructor_arg_type(info, builder_type)?;
+            if let ArgType::Plain(plain_type) = arg_type {
+                // No fancy builder function, we can just move the value directly into the struct.
+                params.push(quote! { #param_name: #plain_type });
+                if is_tuple {
+                    builder_struct_fields.push(quote! { #plain_type });
+                } else {
+                    builder_struct_fields.push(quote! { #param_name: #plain_type });
+                }
+                builder_struct_field_names.push(param_field.builder_member());
+                doc_table += &format!(
+                    "| `{}` | Directly pass in the value this field should contain{} |\n",
+                    param_field.accessor_name(),
+                    moved_into
+                );
+            } else if let ArgType::TraitBound(bound_type) = arg_type {
+                // Trait bounds are much trickier. We need a special syntax to accept them in the
+                // constructor, and generic parameters need to be added to the builder struct to make
+                // it work.
+                let builder_name = param_field.builder_name();
+                params.push(quote! { #builder_name : impl #bound_type });
                 doc_table += &format!(
-                    "{}: &{}_",
-                    borrowed_name,
-                    if borrow.mutable { "mut " } else { "" },
+                    "| `{}` | Use a function or closure: `({}) -> {}: _`{} | \n",
+                    if is_tuple {
+                        param_field.accessor_name()
+                    } else {
+                        builder_name.to_string()
+                    },
+                    param_field.builder_doc_params(info),
+                    param_field.accessor_name(),
+                    moved_into
                 );
-                if index < field.borrows.len() - 1 {
-                    doc_table += ", ";
+                let builder_args = param_field.builder_args(info);
+                let call = quote! { #builder_name (#(#builder_args),*) };
+                if moved_into.is_empty() {
+                    builder_call = Some(call);
+                } else {
+                    consumed_builder_calls.push((param_name, call));
                 }
+                let generic_type_name = param_field.builder_generic_name();
+
+                builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
+                builder_struct_generic_consumers.push(quote! { #generic_type_name });
+                if is_tuple {
+                    builder_struct_fields.push(quote! { #generic_type_name });
+                } else {
+                    builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
+                }
+                builder_struct_field_names.push(param_field.builder_member());
             }
-            doc_table += &format!(") -> {}: _` | \n", field_name);
-            if builder_type.is_async() {
-                code.push(quote! { let #field_name = #builder_name (#(#builder_args),*).await; });
-            } else {
-                code.push(quote! { let #field_name = #builder_name (#(#builder_args),*); });
+        }
+        // Consumed fields with builders are built right before the field consuming them.
+        let mut consumed_code = Vec::new();
+        for consumed in &field.consumes {
+            let position = consumed_builder_calls
+                .iter()
+                .position(|(name, _)| **name == consumed.name);
+            if let Some(position) = position {
+                let (name, call) = consumed_builder_calls.remove(position);
+                if builder_type.is_async() {
+                    consumed_code.push(quote! { let #name = #call.await; });
+                } else {
+                    consumed_code.push(quote! { let #name = #call; });
+                }
             }
-            let generic_type_name =
-                format_ident!("{}Builder_", to_class_case(field_name.to_string().as_str()));
+        }
+        let mut store_code = Vec::new();
+        if field.storage == FieldStorage::Block {
+            store_code.push(field.store_in_place(&quote! { block_slot.as_ptr() }));
+            store_code.push(quote! { drop_guard.push(); });
+        } else if info.pinned {
+            store_code.push(field.store_in_place(&this_ptr));
+            store_code.push(quote! { drop_guard.push(); });
+        } else {
+            if field.is_borrowed() {
+                let boxed = field.boxed();
+                if field.field_type == FieldType::BorrowedMut {
+                    store_code.push(quote! { let mut #field_name = #boxed; });
+                } else {
+                    store_code.push(quote! { let #field_name = #boxed; });
+                }
+            };
 
-            builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
-            builder_struct_generic_consumers.push(quote! { #generic_type_name });
-            builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
-            builder_struct_field_names.push(quote! { #builder_name });
-        }
-        if field.is_borrowed() {
-            let boxed = field.boxed();
-            if field.field_type == FieldType::BorrowedMut {
-                code.push(quote! { let mut #field_name = #boxed; });
+            if field.field_type == FieldType::Borrowed {
+                store_code.push(field.make_illegal_static_reference());
+            } else if field.field_type == FieldType::BorrowedMut {
+                store_code.push(field.make_illegal_static_mut_reference());
+            }
+        }
+        code.extend(consumed_code.iter().cloned());
+        if let Some(builder_call) = &builder_call {
+            if builder_type.is_async() {
+                code.push(quote! { let #field_name = #builder_call.await; });
             } else {
-                code.push(quote! { let #field_name = #boxed; });
+                code.push(quote! { let #field_name = #builder_call; });
             }
+        }
+        code.extend(store_code.iter().cloned());
+        // Concurrently built fields await the builders of the fields they consume first.
+        let builder_call = match builder_call {
+            Some(builder_call) if !consumed_code.is_empty() => Some(quote! {
+                async move {
+                    #(#consumed_code)*
+                    #builder_call.await
+                }
+            }),
+            builder_call => builder_call,
         };
+        field_steps.push((builder_call, store_code));
+    }
+    let (concurrent_code, concurrent_stored) = if builder_type.is_async() {
+        make_concurrent_code(info, field_steps)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    let stored: Vec<_> = info
+        .fields
+        .iter()
+        .filter(|field| info.is_stored_in_place(field))
+        .collect();
 
-        if field.field_type == FieldType::Borrowed {
-            code.push(field.make_illegal_static_reference());
-        } else if field.field_type == FieldType::BorrowedMut {
-            code.push(field.make_illegal_static_mut_reference());
-        }
+    if let Some((param, builder_field, builder_member)) = info.allocator_arg() {
+        params.push(param);
+        builder_struct_fields.push(builder_field);
+        builder_struct_field_names.push(builder_member);
+        doc_table += "| `allocator` | The allocator used to box borrowed fields |\n";
     }
 
     let documentation = if !options.do_no_doc {
@@ -148,26 +239,148 @@ pub fn create_builder_and_constructor(
         quote! { #[doc(hidden)] }
     };
 
-    let constructor_fn = match builder_type {
-        BuilderType::AsyncSend => quote! { async fn new_async_send },
-        BuilderType::Async => quote! { async fn new_async },
-        BuilderType::Sync => quote! { fn new },
+    let constructor_fn_name = match builder_type {
+        BuilderType::AsyncSend => "new_async_send",
+        BuilderType::Async => "new_async",
+        BuilderType::Sync => "new",
     };
-    let field_names: Vec<_> = info.fields.iter().map(|field| field.name.clone()).collect();
-    let internal_ident = &info.internal_ident;
-    let constructor_def = quote! {
-        #documentation
-        #vis #constructor_fn(#(#params),*) -> #struct_name <#(#generic_args),*> {
-            #(#code)*
-            unsafe {
-                Self {
-                    actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
-                        #(#field_names),*
-                    })
+    let constructor_fn = {
+        let name = format_ident!("{}", constructor_fn_name);
+        if builder_type.is_async() {
+            quote! { async fn #name }
+        } else {
+            quote! { fn #name }
+        }
+    };
+    let mut field_names: Vec<_> = info
+        .fields
+        .iter()
+        .filter(|field| field.storage != FieldStorage::Block)
+        .map(|field| {
+            let name = &field.name;
+            quote! { #name }
+        })
+        .collect();
+    if info.single_allocation {
+        field_names.push(quote! { _block: block_slot.assume_init_block() });
+    }
+    for (_ty, ident) in info.generic_consumers() {
+        field_names.push(quote! { #ident: ::core::marker::PhantomData });
+    }
+    let constructed_type = info.constructed_type();
+    let block_slot_def = info.make_block_slot();
+    let make_body = |code: &[TokenStream], stored: &[&StructFieldInfo]| {
+        if info.pinned {
+            let this_def = if builder_type.is_async() {
+                quote! {}
+            } else {
+                quote! { let this = slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>(); }
+            };
+            let drop_guard_def = info.make_drop_guard(
+                &quote! { slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>() },
+                stored,
+            );
+            quote! {
+                let slot = ::ouroboros::macro_help::UninitBox::<Self>::new();
+                #this_def
+                #drop_guard_def
+                #(#code)*
+                drop_guard.disarm();
+                // The remaining fields are zero-sized markers which need no initialization.
+                unsafe { slot.assume_init() }
+            }
+        } else if info.single_allocation {
+            let drop_guard_def = info.make_drop_guard(&quote! { block_slot.as_ptr() }, stored);
+            quote! {
+                #block_slot_def
+                #drop_guard_def
+                #(#code)*
+                drop_guard.disarm();
+                unsafe {
+                    Self {
+                        actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
+                            #(#field_names),*
+                        })
+                    }
+                }
+            }
+        } else {
+            quote! {
+                #(#code)*
+                unsafe {
+                    Self {
+                        actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
+                            #(#field_names),*
+                        })
+                    }
                 }
             }
         }
     };
+    let body = make_body(&code, &stored);
+    let mut constructor_def = quote! {
+        #documentation
+        #vis #constructor_fn(#(#params),*) -> #constructed_type {
+            #body
+        }
+    };
+    if builder_type.is_async() {
+        let concurrent_documentation = if !options.do_no_doc {
+            let concurrent_documentation = format!(
+                concat!(
+                    "Like [`{0}()`](Self::{0}), but the builders of fields which do not borrow ",
+                    "each other, directly or indirectly, are awaited concurrently instead of one ",
+                    "after another. (See also [`{1}::build_concurrent()`]({1}::build_concurrent).) ",
+                    "The arguments are as follows:\n\n| Argument | Suggested Use |\n| --- | --- |\n",
+                ),
+                constructor_fn_name, builder_struct_name
+            ) + &doc_table;
+            quote! { #[doc=#concurrent_documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let concurrent_fn = format_ident!("{}_concurrent", constructor_fn_name);
+        let concurrent_body = make_body(&concurrent_code, &concurrent_stored);
+        constructor_def.extend(quote! {
+            #concurrent_documentation
+            #vis async fn #concurrent_fn(#(#params),*) -> #constructed_type {
+                #concurrent_body
+            }
+        });
+    }
+    if info.pinned && !builder_type.is_async() {
+        let in_place_documentation = if !options.do_no_doc {
+            let in_place_documentation = format!(
+                concat!(
+                    "Like [`new()`](Self::new), but constructs the struct in `slot` instead ",
+                    "of allocating memory for it. The struct is not dropped when `slot` goes ",
+                    "out of scope, so anything it owns is leaked unless the caller drops it in ",
+                    "place. (See also [`{0}::build_in_place()`]({0}::build_in_place).) The ",
+                    "arguments are as follows:\n\n| Argument | Suggested Use |\n| --- | --- |\n",
+                ),
+                builder_struct_name
+            ) + &doc_table;
+            quote! { #[doc=#in_place_documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let in_place_drop_guard_def = info.make_drop_guard(&quote! { this }, &stored);
+        constructor_def.extend(quote! {
+            #in_place_documentation
+            #vis fn new_in_place<'slot>(
+                slot: ::core::pin::Pin<&'slot mut ::core::mem::MaybeUninit<Self>>,
+                #(#params),*
+            ) -> ::core::pin::Pin<&'slot mut Self> {
+                let slot = unsafe { ::core::pin::Pin::get_unchecked_mut(slot) };
+                let this = slot.as_mut_ptr().cast::<#internal_ident<#(#generic_args),*>>();
+                #in_place_drop_guard_def
+                #(#code)*
+                drop_guard.disarm();
+                // The remaining fields are zero-sized markers which need no initialization.
+                unsafe { ::core::pin::Pin::new_unchecked(slot.assume_init_mut()) }
+            }
+        });
+    }
     let generic_where = &info.generics.where_clause;
     let builder_fn = if builder_type.is_async() {
         quote! { async fn build }
@@ -191,17 +404,131 @@ pub fn create_builder_and_constructor(
             )
         },
     };
+    let builder_struct_def = if is_tuple {
+        quote! {
+            #vis struct #builder_struct_name <#(#builder_struct_generic_producers),*> (
+                #(#vis #builder_struct_fields),*
+            ) #generic_where;
+        }
+    } else {
+        quote! {
+            #vis struct #builder_struct_name <#(#builder_struct_generic_producers),*> #generic_where {
+                #(#vis #builder_struct_fields),*
+            }
+        }
+    };
+    let build_in_place_fn = if info.pinned && !builder_type.is_async() {
+        let build_in_place_fn_documentation = format!(
+            concat!(
+                "Calls [`{0}::new_in_place()`]({0}::new_in_place) using the provided values. ",
+                "This is preferable over calling `new_in_place()` directly for the reasons ",
+                "listed above. "
+            ),
+            info.ident
+        );
+        quote! {
+            #[doc=#build_in_place_fn_documentation]
+            #vis fn build_in_place<'slot>(
+                self,
+                slot: ::core::pin::Pin<&'slot mut ::core::mem::MaybeUninit<#struct_name <#(#generic_args),*>>>,
+            ) -> ::core::pin::Pin<&'slot mut #struct_name <#(#generic_args),*>> {
+                #struct_name::new_in_place(
+                    slot,
+                    #(self.#builder_struct_field_names),*
+                )
+            }
+        }
+    } else if builder_type.is_async() {
+        let build_concurrent_fn_documentation = format!(
+            concat!(
+                "Calls [`{0}::{1}_concurrent()`]({0}::{1}_concurrent) using the provided values. ",
+                "Unlike [`build()`](Self::build), the builders of fields which do not borrow each ",
+                "other are awaited concurrently. "
+            ),
+            info.ident, constructor_fn_name
+        );
+        let concurrent_fn = format_ident!("{}_concurrent", constructor_fn_name);
+        quote! {
+            #[doc=#build_concurrent_fn_documentation]
+            #vis async fn build_concurrent(self) -> #constructed_type {
+                #struct_name::#concurrent_fn(
+                    #(self.#builder_struct_field_names),*
+                ).await
+            }
+        }
+    } else {
+        quote! {}
+    };
     let builder_def = quote! {
         #builder_documentation
-        #vis struct #builder_struct_name <#(#builder_struct_generic_producers),*> #generic_where {
-            #(#vis #builder_struct_fields),*
-        }
+        #builder_struct_def
         impl<#(#builder_struct_generic_producers),*> #builder_struct_name <#(#builder_struct_generic_consumers),*> #generic_where {
             #[doc=#build_fn_documentation]
-            #vis #builder_fn(self) -> #struct_name <#(#generic_args),*> {
+            #vis #builder_fn(self) -> #constructed_type {
                 #builder_code
             }
+            #build_in_place_fn
         }
     };
     Ok((builder_struct_name, builder_def, constructor_def))
 }
+
+/// Orders the builder calls of an async constructor by how deeply each field depends on other
+/// builders, so that the futures of fields which do not borrow each other are awaited together.
+/// Fields without a builder are stored first, after that every level of builders is joined and
+/// stored before the next level is started. Also returns the fields which are stored in place, in
+/// the order they are stored in.
+fn make_concurrent_code(
+    info: &StructInfo,
+    field_steps: Vec<(Option<TokenStream>, Vec<TokenStream>)>,
+) -> (Vec<TokenStream>, Vec<&StructFieldInfo>) {
+    let mut levels: Vec<usize> = Vec::with_capacity(field_steps.len());
+    for (field, (builder_call, _)) in info.fields.iter().zip(&field_steps) {
+        let level = if builder_call.is_some() {
+            // Fields can only borrow fields declared before them, so their levels are known.
+            1 + field
+                .all_borrows()
+                .map(|borrow| levels[borrow.index])
+                .max()
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        levels.push(level);
+    }
+    let max_level = levels.iter().copied().max().unwrap_or(0);
+    let mut code = Vec::new();
+    let mut stored = Vec::new();
+    for level in 0..=max_level {
+        let steps: Vec<_> = info
+            .fields
+            .iter()
+            .zip(&field_steps)
+            .zip(&levels)
+            .filter(|(_, field_level)| **field_level == level)
+            .map(|(step, _)| step)
+            .collect();
+        let calls: Vec<_> = steps
+            .iter()
+            .filter_map(|(field, (builder_call, _))| {
+                builder_call.as_ref().map(|call| (&field.name, call))
+            })
+            .collect();
+        if let Some(((last_name, last_call), rest)) = calls.split_last() {
+            let mut pattern = quote! { #last_name };
+            let mut future = quote! { #last_call };
+            for (name, call) in rest.iter().rev() {
+                pattern = quote! { (#name, #pattern) };
+                future = quote! { ::ouroboros::macro_help::join(#call, #future) };
+            }
+            code.push(quote! { let #pattern = #future.await; });
+        }
+        for (field, (_, store_code)) in steps {
+            code.extend(store_code.iter().cloned());
+            if info.is_stored_in_place(field) {
+                stored.push(field);
+            }
+        }
+    }
+    (code, stored)
+}
@@ -1,7 +1,9 @@
-use crate::info_structures::{Derive, StructInfo};
+use crate::info_structures::{
+    DebugFormat, Derive, FieldStorage, StableDerefType, StructFieldInfo, StructInfo,
+};
 use proc_macro2::TokenStream;
-use quote::quote;
-use syn::{Error, GenericParam, TypeParamBound};
+use quote::{format_ident, quote};
+use syn::{ext::IdentExt, Error, GenericParam, TypeParamBound, WhereClause};
 
 fn add_trait_bound(param: &GenericParam, bound: &TypeParamBound) -> GenericParam {
     let mut new = param.clone();
@@ -29,24 +31,48 @@ fn impl_trait(info: &StructInfo, trait_name: TypeParamBound, body: TokenStream)
     }
 }
 
-fn impl_debug(info: &StructInfo) -> Result<TokenStream, Error> {
-    let fields = info
-        .fields
+/// Fields which are inspected by derived traits. Mutably borrowed fields cannot be accessed at all
+/// and fields marked with `#[ouroboros(skip)]` are left out on purpose.
+fn inspected_fields(info: &StructInfo) -> impl Iterator<Item = &StructFieldInfo> {
+    info.fields
         .iter()
-        .filter(|field| !field.is_mutably_borrowed())
+        .filter(|field| !field.is_mutably_borrowed() && !field.skip_derives)
+}
+
+fn impl_debug(info: &StructInfo) -> Result<TokenStream, Error> {
+    let fields = inspected_fields(info)
+        .filter(|field| !matches!(field.debug_format, DebugFormat::Skip))
         .map(|field| {
             let name = &field.name;
-            quote! {
-                field(stringify!(#name), &safe_self.#name)
+            let value = if let DebugFormat::With(path) = &field.debug_format {
+                quote! {
+                    &::ouroboros::macro_help::debug_with(safe_self.#name, |value, f| #path(value, f))
+                }
+            } else {
+                quote! { &safe_self.#name }
+            };
+            if field.tuple_index.is_some() {
+                quote! {
+                    field(#value)
+                }
+            } else {
+                quote! {
+                    field(stringify!(#name), #value)
+                }
             }
         })
         .collect::<Vec<_>>();
     let trait_name = syn::parse_quote! { ::core::fmt::Debug };
     let struct_name = &info.ident;
+    let debug_builder = if info.is_tuple() {
+        quote! { debug_tuple }
+    } else {
+        quote! { debug_struct }
+    };
     let body = quote! {
         fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
             self.with(|safe_self| {
-                f.debug_struct(stringify!(#struct_name))
+                f.#debug_builder(stringify!(#struct_name))
                 #(.#fields)*
                 .finish()
             })
@@ -56,10 +82,7 @@ fn impl_debug(info: &StructInfo) -> Result<TokenStream, Error> {
 }
 
 fn impl_partial_eq(info: &StructInfo) -> Result<TokenStream, Error> {
-    let fields = info
-        .fields
-        .iter()
-        .filter(|field| !field.is_mutably_borrowed())
+    let fields = inspected_fields(info)
         .map(|field| {
             let name = &field.name;
             quote! {
@@ -86,13 +109,380 @@ fn impl_eq(info: &StructInfo) -> Result<TokenStream, Error> {
     Ok(impl_trait(info, trait_name, body))
 }
 
+fn impl_hash(info: &StructInfo) -> Result<TokenStream, Error> {
+    let fields = inspected_fields(info)
+        .map(|field| {
+            let name = &field.name;
+            quote! {
+                ::core::hash::Hash::hash(&*safe_self.#name, state);
+            }
+        })
+        .collect::<Vec<_>>();
+    let trait_name = syn::parse_quote! { ::core::hash::Hash };
+    let body = quote! {
+        fn hash<Hasher_: ::core::hash::Hasher>(&self, state: &mut Hasher_) {
+            self.with(|safe_self| {
+                #(#fields)*
+            })
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_partial_ord(info: &StructInfo) -> Result<TokenStream, Error> {
+    let fields = inspected_fields(info)
+        .map(|field| {
+            let name = &field.name;
+            quote! {
+                match ::core::cmp::PartialOrd::partial_cmp(&*safe_self.#name, &*safe_other.#name) {
+                    ::core::option::Option::Some(::core::cmp::Ordering::Equal) => {}
+                    ordering => return ordering,
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let trait_name = syn::parse_quote! { ::core::cmp::PartialOrd };
+    let body = quote! {
+        fn partial_cmp(&self, other: &Self) -> ::core::option::Option<::core::cmp::Ordering> {
+            self.with(|safe_self| {
+                other.with(|safe_other| {
+                    #(#fields)*
+                    ::core::option::Option::Some(::core::cmp::Ordering::Equal)
+                })
+            })
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_ord(info: &StructInfo) -> Result<TokenStream, Error> {
+    let fields = inspected_fields(info)
+        .map(|field| {
+            let name = &field.name;
+            quote! {
+                match ::core::cmp::Ord::cmp(&*safe_self.#name, &*safe_other.#name) {
+                    ::core::cmp::Ordering::Equal => {}
+                    ordering => return ordering,
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let trait_name = syn::parse_quote! { ::core::cmp::Ord };
+    let body = quote! {
+        fn cmp(&self, other: &Self) -> ::core::cmp::Ordering {
+            self.with(|safe_self| {
+                other.with(|safe_other| {
+                    #(#fields)*
+                    ::core::cmp::Ordering::Equal
+                })
+            })
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_clone(info: &StructInfo) -> Result<TokenStream, Error> {
+    if info.pinned {
+        return Err(Error::new(
+            info.ident.span(),
+            "Clone cannot be derived for pinned structs, as they can only be constructed in place.",
+        ));
+    }
+    let mut args = Vec::new();
+    for field in &info.fields {
+        if field.is_mutably_borrowed() {
+            return Err(Error::new(
+                field.name.span(),
+                format!(
+                    concat!(
+                        "Clone cannot be derived because {} is borrowed mutably, so its current ",
+                        "value cannot be accessed."
+                    ),
+                    field.accessor_name()
+                ),
+            ));
+        }
+        let name = &field.name;
+        let borrowed_names: Vec<_> = field
+            .borrows
+            .iter()
+            .map(|borrow| &info.fields[borrow.index].name)
+            .collect();
+        let borrowed = if borrowed_names.len() == 1 {
+            quote! { #(#borrowed_names)* }
+        } else {
+            quote! { (#(#borrowed_names),*) }
+        };
+        let arg = match (&field.clone_with, field.borrows.is_empty()) {
+            // Head fields are cloned by the function instead of by Clone::clone().
+            (Some(clone_with), true) if !field.self_referencing => quote! {
+                #clone_with(safe_self.#name)
+            },
+            (Some(clone_with), true) => quote! { #clone_with() },
+            (Some(clone_with), false) => quote! {
+                |#(#borrowed_names),*| #clone_with(#(#borrowed_names),*)
+            },
+            (None, true) if field.self_referencing => {
+                return Err(Error::new(
+                    field.name.span(),
+                    format!(
+                        concat!(
+                            "Clone cannot be derived because {} uses 'this without borrowing ",
+                            "anything, specify how to create it with #[clone_with()]."
+                        ),
+                        field.accessor_name()
+                    ),
+                ))
+            }
+            // Shared pointers are stored as they are, so cloning them shares their contents.
+            (None, true)
+                if matches!(
+                    field.storage,
+                    FieldStorage::StableDeref(StableDerefType::Arc | StableDerefType::Rc)
+                ) =>
+            {
+                quote! {
+                    ::core::clone::Clone::clone(&unsafe { self.actual_data.assume_init_ref() }.#name)
+                }
+            }
+            // The other containers copy their contents when cloned, which is all that can be
+            // accessed of them.
+            (None, true) if matches!(field.storage, FieldStorage::StableDeref(_)) => quote! {
+                ::core::convert::From::from(
+                    ::ouroboros::macro_help::alloc::borrow::ToOwned::to_owned(safe_self.#name)
+                )
+            },
+            (None, true) => quote! { ::core::clone::Clone::clone(safe_self.#name) },
+            (None, false) => quote! {
+                |#(#borrowed_names),*| ::ouroboros::RebaseClone::rebase_clone(safe_self.#name, #borrowed)
+            },
+        };
+        args.push(arg);
+    }
+    let trait_name = syn::parse_quote! { ::core::clone::Clone };
+    let body = quote! {
+        fn clone(&self) -> Self {
+            self.with(|safe_self| {
+                Self::new(#(#args),*)
+            })
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_default(info: &StructInfo) -> Result<TokenStream, Error> {
+    if info.pinned {
+        return Err(Error::new(
+            info.ident.span(),
+            concat!(
+                "Default cannot be derived for pinned structs, as they can only be constructed ",
+                "in place."
+            ),
+        ));
+    }
+    let mut args = Vec::new();
+    for field in &info.fields {
+        let borrowed_names: Vec<_> = field
+            .borrows
+            .iter()
+            .map(|borrow| &info.fields[borrow.index].name)
+            .collect();
+        let borrowed = if borrowed_names.len() == 1 {
+            quote! { #(#borrowed_names)* }
+        } else {
+            quote! { (#(#borrowed_names),*) }
+        };
+        let arg = match (&field.default_with, field.borrows.is_empty()) {
+            (Some(default_with), true) => quote! { #default_with() },
+            (Some(default_with), false) => quote! {
+                |#(#borrowed_names),*| #default_with(#(#borrowed_names),*)
+            },
+            (None, true) => quote! { ::core::default::Default::default() },
+            (None, false) => quote! {
+                |#(#borrowed_names),*| ::ouroboros::DefaultFrom::default_from(#borrowed)
+            },
+        };
+        args.push(arg);
+    }
+    let trait_name = syn::parse_quote! { ::core::default::Default };
+    let body = quote! {
+        fn default() -> Self {
+            Self::new(#(#args),*)
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_serialize(info: &StructInfo) -> Result<TokenStream, Error> {
+    let mut fields = Vec::new();
+    for field in &info.fields {
+        if field.skip_derives || (field.self_referencing && !field.serialize) {
+            continue;
+        }
+        if field.is_mutably_borrowed() {
+            return Err(Error::new(
+                field.name.span(),
+                format!(
+                    concat!(
+                        "Serialize cannot be derived because {} is borrowed mutably, so its ",
+                        "current value cannot be accessed."
+                    ),
+                    field.accessor_name()
+                ),
+            ));
+        }
+        let name = &field.name;
+        // This matches the name of the field in Heads, which is what Deserialize expects.
+        let key = name.unraw().to_string();
+        fields.push(quote! {
+            ::ouroboros::macro_help::serde::ser::SerializeStruct::serialize_field(
+                &mut state,
+                #key,
+                safe_self.#name,
+            )?;
+        });
+    }
+    let struct_name = info.ident.to_string();
+    let field_count = fields.len();
+    let trait_name = syn::parse_quote! { ::ouroboros::macro_help::serde::Serialize };
+    let body = quote! {
+        fn serialize<Serializer_>(
+            &self,
+            serializer: Serializer_,
+        ) -> ::core::result::Result<Serializer_::Ok, Serializer_::Error>
+        where
+            Serializer_: ::ouroboros::macro_help::serde::Serializer,
+        {
+            self.with(|safe_self| {
+                let mut state = ::ouroboros::macro_help::serde::Serializer::serialize_struct(
+                    serializer,
+                    #struct_name,
+                    #field_count,
+                )?;
+                #(#fields)*
+                ::ouroboros::macro_help::serde::ser::SerializeStruct::end(state)
+            })
+        }
+    };
+    Ok(impl_trait(info, trait_name, body))
+}
+
+fn impl_deserialize(info: &StructInfo) -> Result<TokenStream, Error> {
+    if info.pinned {
+        return Err(Error::new(
+            info.ident.span(),
+            concat!(
+                "Deserialize cannot be derived for pinned structs, as they can only be ",
+                "constructed in place."
+            ),
+        ));
+    }
+    let mut members = Vec::new();
+    let mut values = Vec::new();
+    for field in &info.fields {
+        let name = &field.name;
+        members.push(field.builder_member());
+        if !field.self_referencing {
+            values.push(quote! { heads.#name });
+            continue;
+        }
+        let deserialize_with = if let Some(path) = &field.deserialize_with {
+            path
+        } else {
+            return Err(Error::new(
+                field.name.span(),
+                format!(
+                    concat!(
+                        "Deserialize cannot be derived because {} is not a head field, specify ",
+                        "how to create it from the deserialized head fields with ",
+                        "#[deserialize_with()]."
+                    ),
+                    field.accessor_name()
+                ),
+            ));
+        };
+        if field.borrows.is_empty() {
+            values.push(quote! { #deserialize_with() });
+        } else {
+            let borrowed_names: Vec<_> = field
+                .borrows
+                .iter()
+                .map(|borrow| &info.fields[borrow.index].name)
+                .collect();
+            values.push(quote! {
+                |#(#borrowed_names),*| #deserialize_with(#(#borrowed_names),*)
+            });
+        }
+    }
+    let struct_name = &info.ident;
+    let builder_name = format_ident!("{}Builder", struct_name);
+    let generic_params = info.generic_params();
+    let generic_args = info.generic_arguments();
+    let mut where_clause: WhereClause = match &info.generics.where_clause {
+        Some(clause) => clause.clone(),
+        None => syn::parse_quote! { where },
+    };
+    where_clause.predicates.push(syn::parse_quote! {
+        Heads<#(#generic_args),*>: ::ouroboros::macro_help::serde::Deserialize<'de>
+    });
+    Ok(quote! {
+        impl <'de, #generic_params> ::ouroboros::macro_help::serde::Deserialize<'de>
+            for #struct_name <#(#generic_args),*> #where_clause
+        {
+            fn deserialize<Deserializer_>(
+                deserializer: Deserializer_,
+            ) -> ::core::result::Result<Self, Deserializer_::Error>
+            where
+                Deserializer_: ::ouroboros::macro_help::serde::Deserializer<'de>,
+            {
+                let heads = <Heads<#(#generic_args),*> as ::ouroboros::macro_help::serde::Deserialize<'de>>::deserialize(deserializer)?;
+                ::core::result::Result::Ok(#builder_name {
+                    #(#members: #values),*
+                }.build())
+            }
+        }
+    })
+}
+
+/// Clone, Default and Deserialize create new instances with `new()`, which needs the values of
+/// consumed fields that the struct does not store.
+fn check_no_consumed_fields(info: &StructInfo, trait_name: &str) -> Result<(), Error> {
+    match info.fields.iter().find(|field| !field.consumes.is_empty()) {
+        Some(field) => Err(Error::new(
+            field.name.span(),
+            format!(
+                concat!(
+                    "{} cannot be derived because {} consumes other fields, which are not stored ",
+                    "in the struct."
+                ),
+                trait_name,
+                field.accessor_name()
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
 pub fn create_derives(info: &StructInfo) -> Result<TokenStream, Error> {
     let mut impls = Vec::new();
     for derive in &info.derives {
+        match derive {
+            Derive::Clone => check_no_consumed_fields(info, "Clone")?,
+            Derive::Default => check_no_consumed_fields(info, "Default")?,
+            Derive::Deserialize => check_no_consumed_fields(info, "Deserialize")?,
+            _ => (),
+        }
         match derive {
             Derive::Debug => impls.push(impl_debug(info)?),
             Derive::PartialEq => impls.push(impl_partial_eq(info)?),
             Derive::Eq => impls.push(impl_eq(info)?),
+            Derive::Clone => impls.push(impl_clone(info)?),
+            Derive::Default => impls.push(impl_default(info)?),
+            Derive::Hash => impls.push(impl_hash(info)?),
+            Derive::PartialOrd => impls.push(impl_partial_ord(info)?),
+            Derive::Ord => impls.push(impl_ord(info)?),
+            Derive::Serialize => impls.push(impl_serialize(info)?),
+            Derive::Deserialize => impls.push(impl_deserialize(info)?),
         }
     }
     Ok(quote! { #(#impls)* })
@@ -1,40 +1,146 @@
 use proc_macro2::TokenStream;
 use quote::quote;
 
-use crate::info_structures::{Options, StructInfo};
+use crate::info_structures::{FieldStorage, Options, StructFieldInfo, StructInfo};
 
-/// Returns the Heads struct and a function to convert the original struct into a Heads instance.
-pub fn make_into_heads(info: &StructInfo, options: Options) -> (TokenStream, TokenStream) {
-    let visibility = if options.do_pub_extras {
-        info.vis.clone()
-    } else {
-        syn::parse_quote! { pub(super) }
-    };
+/// Returns code which takes apart the internal struct `this` in the reverse order the fields were
+/// declared in, moving the fields for which `keep` returns true into variables of the same name
+/// and dropping the rest. Also returns initializers and declarations for the kept fields.
+fn take_fields(
+    info: &StructInfo,
+    visibility: &syn::Visibility,
+    keep: impl Fn(&StructFieldInfo) -> bool,
+) -> (Vec<TokenStream>, Vec<TokenStream>, Vec<TokenStream>) {
     let mut code = Vec::new();
     let mut field_initializers = Vec::new();
-    let mut head_fields = Vec::new();
-    let internal_struct = &info.internal_ident;
+    let mut kept_fields = Vec::new();
     // Drop everything in the reverse order of what it was declared in. Fields that come later
     // are only dependent on fields that came before them.
     for field in info.fields.iter().rev() {
         let field_name = &field.name;
-        if field.self_referencing {
-            // Heads are fields that do not borrow anything.
+        let keep = keep(field);
+        if info.pinned {
+            // Fields of pinned structs must not be moved until everything borrowing them is gone.
+            code.push(field.take_from_place(&quote! { this }, keep));
+        } else if field.storage == FieldStorage::Block {
+            code.push(field.take_from_place(&quote! { block_slot.as_ptr() }, keep));
+        } else if !keep {
             code.push(quote! { ::core::mem::drop(this.#field_name); });
         } else {
             code.push(quote! { let #field_name = this.#field_name; });
-            if field.is_borrowed() {
-                field_initializers
-                    .push(quote! { #field_name: ::ouroboros::macro_help::unbox(#field_name) });
-            } else {
-                field_initializers.push(quote! { #field_name });
-            }
+        }
+        if keep {
+            let value = field.unstore(quote! { #field_name });
+            field_initializers.push(quote! { #field_name: #value });
             let field_type = &field.typ;
-            head_fields.push(quote! { #visibility #field_name: #field_type });
+            kept_fields.push(quote! { #visibility #field_name: #field_type });
         }
     }
+    (code, field_initializers, kept_fields)
+}
+
+/// Returns a function named `fn_name` which converts the original struct into an instance of
+/// `return_struct` using the output of `take_fields`.
+fn make_take_apart_fn(
+    info: &StructInfo,
+    visibility: &syn::Visibility,
+    fn_name: TokenStream,
+    return_struct: TokenStream,
+    code: Vec<TokenStream>,
+    field_initializers: Vec<TokenStream>,
+    documentation: TokenStream,
+) -> TokenStream {
+    let internal_struct = &info.internal_ident;
+    let generic_args = info.generic_arguments();
+    let take_block = if info.single_allocation {
+        quote! {
+            let block_slot = ::ouroboros::macro_help::AliasableBlock::into_uninit(this._block);
+        }
+    } else {
+        quote! {}
+    };
+    if info.pinned {
+        quote! {
+            #documentation
+            #visibility fn #fn_name(self: ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<Self>>) -> #return_struct<#(#generic_args),*> {
+                let slot = unsafe { ::ouroboros::macro_help::UninitBox::from_pinned(self) };
+                let this = slot.as_ptr().cast::<#internal_struct<#(#generic_args),*>>();
+                #(#code)*
+                #return_struct {
+                    #(#field_initializers),*
+                }
+            }
+        }
+    } else {
+        quote! {
+            #documentation
+            #[allow(clippy::drop_ref)]
+            #[allow(clippy::drop_copy)]
+            #[allow(clippy::drop_non_drop)]
+            #visibility fn #fn_name(self) -> #return_struct<#(#generic_args),*> {
+                let this_ptr = &self as *const _;
+                let this: #internal_struct<#(#generic_args),*> = unsafe { ::core::mem::transmute_copy(&*this_ptr) };
+                ::core::mem::forget(self);
+                #take_block
+                #(#code)*
+                #return_struct {
+                    #(#field_initializers),*
+                }
+            }
+        }
+    }
+}
+
+fn make_documentation(options: &Options, documentation: String) -> TokenStream {
+    if !options.do_no_doc {
+        quote! {
+            #[doc=#documentation]
+        }
+    } else {
+        quote! { #[doc(hidden)] }
+    }
+}
+
+/// Returns the Heads struct and a function to convert the original struct into a Heads instance.
+pub fn make_into_heads(info: &StructInfo, options: &Options) -> (TokenStream, TokenStream) {
+    let visibility = if options.do_pub_extras {
+        info.vis.clone()
+    } else {
+        syn::parse_quote! { pub(super) }
+    };
+    let (code, mut field_initializers, head_fields) =
+        take_fields(info, &visibility, |field| !field.self_referencing);
+    let (serde_attributes, serde_skip) = if info.derives_deserialize() {
+        let name = info.ident.to_string();
+        (
+            quote! {
+                #[derive(::ouroboros::macro_help::serde::Deserialize)]
+                #[serde(crate = "::ouroboros::macro_help::serde", rename = #name)]
+            },
+            quote! { #[serde(skip)] },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
+    // Heads left out of Serialize are set to their default value when deserializing, like fields
+    // annotated with #[serde(skip)].
+    let heads = info
+        .fields
+        .iter()
+        .rev()
+        .filter(|field| !field.self_referencing);
+    let mut head_fields: Vec<_> = heads
+        .zip(head_fields)
+        .map(|(field, head_field)| {
+            if field.skip_derives {
+                quote! { #serde_skip #head_field }
+            } else {
+                head_field
+            }
+        })
+        .collect();
     for (ty, ident) in info.generic_consumers() {
-        head_fields.push(quote! { #ident: ::core::marker::PhantomData<#ty> });
+        head_fields.push(quote! { #serde_skip #ident: ::core::marker::PhantomData<#ty> });
         field_initializers.push(quote! { #ident: ::core::marker::PhantomData });
     }
     let documentation = format!(
@@ -48,6 +154,7 @@ pub fn make_into_heads(info: &StructInfo, options: Options) -> (TokenStream, Tok
     let generic_where = &info.generics.where_clause;
     let heads_struct_def = quote! {
         #[doc=#documentation]
+        #serde_attributes
         #visibility struct Heads <#generic_params> #generic_where {
             #(#head_fields),*
         }
@@ -56,30 +163,61 @@ pub fn make_into_heads(info: &StructInfo, options: Options) -> (TokenStream, Tok
         "This function drops all internally referencing fields and returns only the ",
         "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) of this struct."
     ).to_owned();
+    let into_heads_fn = make_take_apart_fn(
+        info,
+        &visibility,
+        quote! { into_heads },
+        quote! { Heads },
+        code,
+        field_initializers,
+        make_documentation(options, documentation),
+    );
+    (heads_struct_def, into_heads_fn)
+}
 
-    let documentation = if !options.do_no_doc {
-        quote! {
-            #[doc=#documentation]
-        }
+/// Returns the Parts struct and a function to convert the original struct into a Parts instance.
+pub fn make_into_parts(info: &StructInfo, options: &Options) -> (TokenStream, TokenStream) {
+    let visibility = if options.do_pub_extras {
+        info.vis.clone()
     } else {
-        quote! { #[doc(hidden)] }
+        syn::parse_quote! { pub(super) }
     };
-
-    let generic_args = info.generic_arguments();
-    let into_heads_fn = quote! {
-        #documentation
-        #[allow(clippy::drop_ref)]
-        #[allow(clippy::drop_copy)]
-        #[allow(clippy::drop_non_drop)]
-        #visibility fn into_heads(self) -> Heads<#(#generic_args),*> {
-            let this_ptr = &self as *const _;
-            let this: #internal_struct<#(#generic_args),*> = unsafe { ::core::mem::transmute_copy(&*this_ptr) };
-            ::core::mem::forget(self);
-            #(#code)*
-            Heads {
-                #(#field_initializers),*
-            }
+    let (code, mut field_initializers, mut part_fields) =
+        take_fields(info, &visibility, |field| field.extractable);
+    for (ty, ident) in info.generic_consumers() {
+        part_fields.push(quote! { #ident: ::core::marker::PhantomData<#ty> });
+        field_initializers.push(quote! { #ident: ::core::marker::PhantomData });
+    }
+    let documentation = format!(
+        concat!(
+            "A struct which contains the ",
+            "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) of [`{0}`]({0}) ",
+            "along with its self-referencing fields which do not use the `'this` lifetime."
+        ),
+        info.ident.to_string()
+    );
+    let generic_params = info.generic_params();
+    let generic_where = &info.generics.where_clause;
+    let parts_struct_def = quote! {
+        #[doc=#documentation]
+        #visibility struct Parts <#generic_params> #generic_where {
+            #(#part_fields),*
         }
     };
-    (heads_struct_def, into_heads_fn)
+    let documentation = concat!(
+        "Like [`into_heads()`](Self::into_heads), but also returns the tail fields which do not ",
+        "use the `'this` lifetime and the fields annotated with `#[extractable]` instead of ",
+        "dropping them."
+    )
+    .to_owned();
+    let into_parts_fn = make_take_apart_fn(
+        info,
+        &visibility,
+        quote! { into_parts },
+        quote! { Parts },
+        code,
+        field_initializers,
+        make_documentation(options, documentation),
+    );
+    (parts_struct_def, into_parts_fn)
 }
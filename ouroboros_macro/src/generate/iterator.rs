@@ -0,0 +1,45 @@
+use crate::{info_structures::StructInfo, utils::replace_this_with_lifetime};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+
+/// Implements `Iterator` for the struct by advancing the field annotated with `#[iterator]`. The
+/// item type is named with 'this replaced by the fake lifetime, so items which borrow from the
+/// struct fail to compile instead of outliving the fields they point to. Fields which are plainly
+/// such iterators, like `slice::Iter<'this, T>`, are already rejected with a clearer error while
+/// parsing.
+pub fn make_iterator_impl(info: &StructInfo) -> TokenStream {
+    let field = if let Some(field) = info.fields.iter().find(|field| field.iterator) {
+        field
+    } else {
+        return quote! {};
+    };
+    let struct_name = &info.ident;
+    let generic_params = info.generic_params();
+    let generic_args = info.generic_arguments();
+    let generic_where = &info.generics.where_clause;
+    let field_type = &field.typ;
+    let item_source = replace_this_with_lifetime(quote! { #field_type }, info.fake_lifetime());
+    let item_type = quote_spanned! { field_type.span() =>
+        <#item_source as ::core::iter::Iterator>::Item
+    };
+    let with_name = format_ident!("with_{}", field.accessor_name());
+    let with_mut_name = format_ident!("with_{}_mut", field.accessor_name());
+    // Spanned at the field, which is where an error about items borrowing 'this should point.
+    let next_body = quote_spanned! { field_type.span() =>
+        self.#with_mut_name(|iterator| ::core::iter::Iterator::next(iterator))
+    };
+    quote! {
+        impl <#generic_params> ::core::iter::Iterator for #struct_name <#(#generic_args),*> #generic_where {
+            type Item = #item_type;
+
+            fn next(&mut self) -> ::core::option::Option<Self::Item> {
+                #next_body
+            }
+
+            fn size_hint(&self) -> (usize, ::core::option::Option<usize>) {
+                self.#with_name(|iterator| ::core::iter::Iterator::size_hint(iterator))
+            }
+        }
+    }
+}
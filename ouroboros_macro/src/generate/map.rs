@@ -0,0 +1,154 @@
+use crate::{
+    info_structures::{FieldStorage, FieldType, Options, StructInfo},
+    utils::replace_this_with_lifetime,
+};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::Error;
+
+/// Whether the struct keeps its only head field in an `AliasableBox` which can be handed over to
+/// another struct. The head must be the only field other fields borrow, so that every reference
+/// held by a self-referencing field points into it.
+fn has_single_boxed_head(info: &StructInfo) -> bool {
+    let mut borrowed = info.fields.iter().filter(|field| field.is_borrowed());
+    let head = match (borrowed.next(), borrowed.next()) {
+        (Some(head), None) => head,
+        _ => return false,
+    };
+    head.field_type == FieldType::Borrowed
+        && head.storage == FieldStorage::Boxed
+        && !head.self_referencing
+        && info
+            .fields
+            .iter()
+            .filter(|field| !field.self_referencing)
+            .count()
+            == 1
+}
+
+/// Implements `MapTarget` for structs made of a boxed head field followed by one tail field
+/// borrowing it, which is what `map_FIELD()` creates.
+pub fn make_map_target_impl(info: &StructInfo) -> Result<TokenStream, Error> {
+    if !info.generics.params.is_empty() || info.fields.len() != 2 || !has_single_boxed_head(info) {
+        return Ok(quote! {});
+    }
+    let head = &info.fields[0];
+    let dependent = &info.fields[1];
+    if dependent.borrows.is_empty() {
+        return Ok(quote! {});
+    }
+    let ident = &info.ident;
+    let internal_ident = &info.internal_ident;
+    let head_name = &head.name;
+    let head_type = &head.typ;
+    let dependent_name = &dependent.name;
+    let dependent_type = &dependent.typ;
+    let stored_dependent_type =
+        replace_this_with_lifetime(quote! { #dependent_type }, info.fake_lifetime());
+    Ok(quote! {
+        unsafe impl<'this> ::ouroboros::macro_help::MapTarget<'this> for #ident {
+            type Head = #head_type;
+            type Dependent = #dependent_type;
+
+            unsafe fn assemble(
+                head: ::ouroboros::macro_help::AliasableBox<#head_type>,
+                dependent: #dependent_type,
+            ) -> Self {
+                Self {
+                    actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
+                        #head_name: head,
+                        #dependent_name: ::core::mem::transmute::<
+                            #dependent_type,
+                            #stored_dependent_type,
+                        >(dependent),
+                    }),
+                }
+            }
+        }
+    })
+}
+
+/// Generates `map_FIELD()` for every tail field of structs whose only borrowed field is a boxed
+/// head. It consumes the struct and creates another one from the same box and a value computed
+/// from the field.
+pub fn make_map_functions(info: &StructInfo, options: &Options) -> Result<Vec<TokenStream>, Error> {
+    if info.pinned || info.single_allocation || info.allocator.is_some() {
+        return Ok(Vec::new());
+    }
+    if !has_single_boxed_head(info) {
+        return Ok(Vec::new());
+    }
+    let head = info
+        .fields
+        .iter()
+        .find(|field| field.is_borrowed())
+        .expect("Checked by has_single_boxed_head.");
+    let head_name = &head.name;
+    let head_type = &head.typ;
+    let internal_ident = &info.internal_ident;
+    let generic_args = info.generic_arguments();
+    let mut map_fns = Vec::new();
+    for field in &info.fields {
+        if !field.self_referencing || field.field_type != FieldType::Tail {
+            continue;
+        }
+        let field_name = &field.name;
+        let field_type = &field.typ;
+        // Every other field is dropped in the reverse order it was declared in.
+        let drop_others = info
+            .fields
+            .iter()
+            .rev()
+            .filter(|other| other.self_referencing && other.name != *field_name)
+            .map(|other| {
+                let other_name = &other.name;
+                quote! { ::core::mem::drop(this.#other_name); }
+            });
+        let map_name = format_ident!("map_{}", field.accessor_name());
+        let documentation = if !options.do_no_doc {
+            let documentation = format!(
+                concat!(
+                    "Consumes this struct to create another self-referencing struct made of a ",
+                    "head field of the same type as `{0}` and one field borrowing it. `map` ",
+                    "receives a reference to `{0}` and the value of `{1}`, and returns the value of ",
+                    "the second field of the new struct. The box holding `{0}` is reused as is, so ",
+                    "the new struct is created without allocating. All other fields are dropped."
+                ),
+                head.accessor_name(),
+                field.accessor_name()
+            );
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let visibility = &field.vis;
+        map_fns.push(quote! {
+            #documentation
+            #[allow(clippy::drop_ref)]
+            #[allow(clippy::drop_copy)]
+            #[allow(clippy::drop_non_drop)]
+            #visibility fn #map_name<Other_>(
+                self,
+                map: impl for<'this> ::core::ops::FnOnce(
+                    &'this #head_type,
+                    #field_type,
+                ) -> <Other_ as ::ouroboros::macro_help::MapTarget<'this>>::Dependent,
+            ) -> Other_
+            where
+                Other_: for<'this> ::ouroboros::macro_help::MapTarget<'this, Head = #head_type>,
+            {
+                let this_ptr = &self as *const _;
+                let this: #internal_ident<#(#generic_args),*> =
+                    unsafe { ::core::mem::transmute_copy(&*this_ptr) };
+                ::core::mem::forget(self);
+                #(#drop_others)*
+                let #field_name = this.#field_name;
+                let head = this.#head_name;
+                let head_ref = unsafe { ::ouroboros::macro_help::change_lifetime(&*head) };
+                let dependent = map(head_ref, #field_name);
+                unsafe { ::ouroboros::macro_help::MapTarget::assemble(head, dependent) }
+            }
+        });
+    }
+    Ok(map_fns)
+}
@@ -2,6 +2,10 @@ pub mod constructor;
 pub mod derives;
 pub mod drop;
 pub mod into_heads;
+pub mod iterator;
+pub mod map;
+pub mod rebuild;
+pub mod reclaim;
 pub mod struc;
 pub mod summon_checker;
 pub mod try_constructor;
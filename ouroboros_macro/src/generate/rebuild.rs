@@ -0,0 +1,286 @@
+use crate::info_structures::{
+    ArgType, BuilderType, FieldType, Options, StructFieldInfo, StructInfo,
+};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::Error;
+
+/// Returns the indexes of all fields which borrow the field at `index`, directly or through other
+/// fields, in the order they were declared in.
+pub fn dependents_of(info: &StructInfo, index: usize) -> Vec<usize> {
+    let mut dependents = Vec::new();
+    for (other_index, other) in info.fields.iter().enumerate().skip(index + 1) {
+        let depends = other
+            .all_borrows()
+            .any(|borrow| borrow.index == index || dependents.contains(&borrow.index));
+        if depends {
+            dependents.push(other_index);
+        }
+    }
+    dependents
+}
+
+/// Returns code which creates the arguments of the builder of `field` from the internal struct
+/// `this` points to. Must be used in an unsafe block.
+pub fn make_builder_args(info: &StructInfo, field: &StructFieldInfo) -> Vec<TokenStream> {
+    field
+        .borrows
+        .iter()
+        .map(|borrow| {
+            let borrowed = &info.fields[borrow.index];
+            if borrow.mutable {
+                let field_ref = borrowed.borrow_stored_mut(quote! { (*this) });
+                quote! { ::ouroboros::macro_help::change_lifetime_mut(#field_ref) }
+            } else {
+                let field_ref = borrowed.borrow_stored(quote! { (&*this) });
+                quote! { ::ouroboros::macro_help::change_lifetime(#field_ref) }
+            }
+        })
+        .collect()
+}
+
+/// Generates `replace_FIELD` for every borrowed field which does not borrow anything itself, and
+/// `rebuild_FIELD` for every self-referencing field. Both drop the field and every field depending
+/// on it, then recreate them in place.
+pub fn make_rebuild_functions(
+    info: &StructInfo,
+    options: &Options,
+) -> Result<Vec<TokenStream>, Error> {
+    let mut rebuild_fns = Vec::new();
+    let internal_ident = &info.internal_ident;
+    let generic_args = info.generic_arguments();
+    let this = quote! { this };
+    for (index, field) in info.fields.iter().enumerate() {
+        let replace = !field.self_referencing;
+        if replace && !field.is_borrowed() {
+            continue;
+        }
+        let dependents = dependents_of(info, index);
+        // The values consumed by builders are gone once the struct is constructed.
+        let consumes = |index: &usize| !info.fields[*index].consumes.is_empty();
+        if dependents.iter().any(consumes) || (!replace && consumes(&index)) {
+            continue;
+        }
+        let mut params = Vec::new();
+        let mut code = Vec::new();
+        let mut doc_table = String::new();
+        for dependent in dependents.iter().rev() {
+            code.push(info.fields[*dependent].drop_value(&this));
+        }
+        if replace {
+            let field_name = &field.name;
+            let field_type = &field.typ;
+            let old_value = field.read_value(&this);
+            params.push(quote! { #field_name: #field_type });
+            doc_table += &format!(
+                "| `{}` | The new value of the field |\n",
+                field.accessor_name()
+            );
+            code.push(quote! { let old_value = #old_value; });
+            code.push(field.write_value(&this));
+        } else {
+            code.push(field.drop_value(&this));
+        }
+        let mut rebuilt = dependents;
+        if !replace {
+            rebuilt.insert(0, index);
+        }
+        for rebuilt_index in rebuilt {
+            let rebuilt_field = &info.fields[rebuilt_index];
+            let field_name = &rebuilt_field.name;
+            let value = match rebuilt_field.make_constructor_arg_type(info, BuilderType::Sync)? {
+                ArgType::Plain(plain_type) => {
+                    params.push(quote! { #field_name: #plain_type });
+                    doc_table += &format!(
+                        "| `{}` | The new value of the field |\n",
+                        rebuilt_field.accessor_name()
+                    );
+                    quote! { #field_name }
+                }
+                ArgType::TraitBound(bound_type) => {
+                    let builder_name = rebuilt_field.builder_name();
+                    let args = make_builder_args(info, rebuilt_field);
+                    params.push(quote! { #builder_name: impl #bound_type });
+                    doc_table += &format!(
+                        "| `{}` | The builder of the field, called as in `new()` |\n",
+                        builder_name
+                    );
+                    quote! { #builder_name(#(#args),*) }
+                }
+            };
+            let write_value = rebuilt_field.write_value(&this);
+            code.push(quote! {
+                {
+                    let #field_name = #value;
+                    #write_value
+                }
+            });
+        }
+        let (fn_name, return_type, return_value, documentation) = if replace {
+            let field_type = &field.typ;
+            (
+                format_ident!("replace_{}", field.accessor_name()),
+                quote! { #field_type },
+                quote! { old_value },
+                format!(
+                    concat!(
+                        "Replaces the value of `{}` and returns the old one. Every field which ",
+                        "borrows it, directly or through other fields, is dropped first and then ",
+                        "rebuilt in the order they were declared in. The process is aborted if a ",
+                        "builder panics, as the struct would be missing fields otherwise. The ",
+                        "arguments are as follows:\n\n| Argument | Suggested Use |\n| --- | --- |\n",
+                    ),
+                    field.accessor_name()
+                ),
+            )
+        } else {
+            (
+                format_ident!("rebuild_{}", field.accessor_name()),
+                quote! { () },
+                quote! {},
+                format!(
+                    concat!(
+                        "Drops `{}` and every field which borrows it, directly or through other ",
+                        "fields, and rebuilds them in the order they were declared in. The ",
+                        "process is aborted if a builder panics, as the struct would be missing ",
+                        "fields otherwise. The arguments are as follows:\n\n",
+                        "| Argument | Suggested Use |\n| --- | --- |\n",
+                    ),
+                    field.accessor_name()
+                ),
+            )
+        };
+        let documentation = if !options.do_no_doc {
+            let documentation = documentation + &doc_table;
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let visibility = &field.vis;
+        let self_param = info.mut_self_param();
+        let internal_mut = info.internal_mut();
+        rebuild_fns.push(quote! {
+            #documentation
+            #visibility fn #fn_name<'outer_borrow>(
+                #self_param,
+                #(#params),*
+            ) -> #return_type {
+                let this: *mut #internal_ident<#(#generic_args),*> = #internal_mut;
+                unsafe {
+                    let guard = ::ouroboros::macro_help::AbortOnUnwind;
+                    #(#code)*
+                    ::core::mem::forget(guard);
+                    #return_value
+                }
+            }
+        });
+    }
+    Ok(rebuild_fns)
+}
+
+/// Generates `try_rebuild_FIELD` and `rebuild_FIELD_async` for every tail field which only
+/// borrows other fields immutably. The new value is built while the old one still exists, so the
+/// struct is left untouched if the builder fails or the future is dropped before completing.
+pub fn make_try_rebuild_functions(
+    info: &StructInfo,
+    options: &Options,
+) -> Result<Vec<TokenStream>, Error> {
+    let mut rebuild_fns = Vec::new();
+    let internal_ident = &info.internal_ident;
+    let generic_args = info.generic_arguments();
+    let this = quote! { this };
+    for field in &info.fields {
+        let eligible = field.field_type == FieldType::Tail
+            && !field.borrows.is_empty()
+            && field.consumes.is_empty()
+            && field.borrows.iter().all(|borrow| !borrow.mutable);
+        if !eligible {
+            continue;
+        }
+        let field_name = &field.name;
+        let builder_name = field.builder_name();
+        let args = make_builder_args(info, field);
+        let drop_value = field.drop_value(&this);
+        let write_value = field.write_value(&this);
+        let visibility = &field.vis;
+        let self_param = info.mut_self_param();
+        let internal_mut = info.internal_mut();
+
+        let try_bound = match field.make_try_constructor_arg_type(info, BuilderType::Sync)? {
+            ArgType::TraitBound(bound) => bound,
+            ArgType::Plain(..) => unreachable!("Fields with borrows always have a builder."),
+        };
+        let try_rebuild_name = format_ident!("try_rebuild_{}", field.accessor_name());
+        let documentation = if !options.do_no_doc {
+            let documentation = format!(
+                concat!(
+                    "Calls `{1}` with the fields `{0}` borrows. If it returns `Ok`, the old value ",
+                    "of `{0}` is dropped and replaced with the new one. If it returns an error, ",
+                    "the struct is left unchanged and the error is returned."
+                ),
+                field.accessor_name(),
+                builder_name
+            );
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        rebuild_fns.push(quote! {
+            #documentation
+            #visibility fn #try_rebuild_name<'outer_borrow, Error_>(
+                #self_param,
+                #builder_name: impl #try_bound,
+            ) -> ::core::result::Result<(), Error_> {
+                let this: *mut #internal_ident<#(#generic_args),*> = #internal_mut;
+                unsafe {
+                    let #field_name = #builder_name(#(#args),*)?;
+                    // The struct would drop the old value again if dropping it panicked.
+                    let guard = ::ouroboros::macro_help::AbortOnUnwind;
+                    #drop_value
+                    #write_value
+                    ::core::mem::forget(guard);
+                }
+                ::core::result::Result::Ok(())
+            }
+        });
+
+        let async_bound = match field.make_constructor_arg_type(info, BuilderType::Async)? {
+            ArgType::TraitBound(bound) => bound,
+            ArgType::Plain(..) => unreachable!("Fields with borrows always have a builder."),
+        };
+        let async_rebuild_name = format_ident!("rebuild_{}_async", field.accessor_name());
+        let documentation = if !options.do_no_doc {
+            let documentation = format!(
+                concat!(
+                    "Awaits the future returned by `{1}` when called with the fields `{0}` ",
+                    "borrows, then drops the old value of `{0}` and replaces it with the output. ",
+                    "If the returned future is dropped before it completes, the struct is left ",
+                    "unchanged."
+                ),
+                field.accessor_name(),
+                builder_name
+            );
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        rebuild_fns.push(quote! {
+            #documentation
+            #visibility async fn #async_rebuild_name<'outer_borrow>(
+                #self_param,
+                #builder_name: impl #async_bound,
+            ) {
+                let this: *mut #internal_ident<#(#generic_args),*> = #internal_mut;
+                let future = unsafe { #builder_name(#(#args),*) };
+                let #field_name = future.await;
+                unsafe {
+                    let guard = ::ouroboros::macro_help::AbortOnUnwind;
+                    #drop_value
+                    #write_value
+                    ::core::mem::forget(guard);
+                }
+            }
+        });
+    }
+    Ok(rebuild_fns)
+}
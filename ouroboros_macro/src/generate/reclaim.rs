@@ -0,0 +1,81 @@
+use crate::{
+    generate::rebuild::make_builder_args,
+    info_structures::{ArgType, BuilderType, FieldType, Options, StructInfo},
+};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::Error;
+
+/// Generates a `reclaim_FIELD` method for every mutably borrowed field whose borrower is a tail
+/// field. It drops the borrower, then passes the mutably borrowed field to a function which
+/// rebuilds the borrower in its place.
+pub fn make_reclaim_functions(
+    info: &StructInfo,
+    options: &Options,
+) -> Result<Vec<TokenStream>, Error> {
+    let mut reclaim_fns = Vec::new();
+    let internal_ident = &info.internal_ident;
+    let generic_args = info.generic_arguments();
+    for (index, field) in info.fields.iter().enumerate() {
+        if field.field_type != FieldType::BorrowedMut {
+            continue;
+        }
+        let borrower = info
+            .fields
+            .iter()
+            .find(|other| other.all_borrows().any(|borrow| borrow.index == index))
+            .expect("Mutably borrowed field has no borrower.");
+        // Anything borrowing the borrower would have to be rebuilt as well.
+        if borrower.field_type != FieldType::Tail {
+            continue;
+        }
+        // The values consumed by its builder are gone once the struct is constructed.
+        if !borrower.consumes.is_empty() {
+            continue;
+        }
+        let bound = match borrower.make_constructor_arg_type(info, BuilderType::Sync)? {
+            ArgType::TraitBound(bound) => bound,
+            ArgType::Plain(..) => unreachable!("Borrowers always have a builder."),
+        };
+        let args = make_builder_args(info, borrower);
+        let drop_borrower = borrower.drop_value(&quote! { this });
+        let write_borrower = borrower.write_value(&quote! { this });
+        let visibility = &field.vis;
+        let borrower_name = &borrower.name;
+        let reclaim_name = format_ident!("reclaim_{}", field.accessor_name());
+        let documentation = if !options.do_no_doc {
+            let documentation = format!(
+                concat!(
+                    "Drops `{1}` to give mutable access to `{0}`, which it borrows. `rebuild` ",
+                    "receives the same arguments as the builder of `{1}` and its result is stored ",
+                    "as the new value of `{1}`. The process is aborted if `rebuild` panics, as ",
+                    "`{1}` would be missing otherwise."
+                ),
+                field.accessor_name(),
+                borrower.accessor_name()
+            );
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let self_param = info.mut_self_param();
+        let internal_mut = info.internal_mut();
+        reclaim_fns.push(quote! {
+            #documentation
+            #visibility fn #reclaim_name<'outer_borrow>(
+                #self_param,
+                rebuild: impl #bound,
+            ) {
+                let this: *mut #internal_ident<#(#generic_args),*> = #internal_mut;
+                unsafe {
+                    let guard = ::ouroboros::macro_help::AbortOnUnwind;
+                    #drop_borrower
+                    let #borrower_name = rebuild(#(#args),*);
+                    #write_borrower
+                    ::core::mem::forget(guard);
+                }
+            }
+        });
+    }
+    Ok(reclaim_fns)
+}
@@ -1,5 +1,5 @@
 use crate::{
-    info_structures::StructInfo,
+    info_structures::{FieldStorage, FieldType, StructInfo},
     utils::{self, replace_this_with_lifetime},
 };
 use proc_macro2::TokenStream;
@@ -18,7 +18,9 @@ pub fn create_actual_struct_def(info: &StructInfo) -> Result<TokenStream, Error>
     let generic_where = &info.generics.where_clause;
     let ident = &info.ident;
     let internal_ident = &info.internal_ident;
+    let attributes = &info.attributes;
     Ok(quote! {
+        #(#attributes)*
         #[repr(transparent)]
         #visibility struct #ident <#generic_params> #generic_where {
             actual_data: ::core::mem::MaybeUninit<#internal_ident<#(#generic_args),*>>,
@@ -26,6 +28,54 @@ pub fn create_actual_struct_def(info: &StructInfo) -> Result<TokenStream, Error>
     })
 }
 
+/// Mutably borrowed fields of pinned and single_allocation structs are stored in an `UnsafeCell`,
+/// which would keep the struct from being `Sync` even if all of its fields are. This implements
+/// `Sync` whenever the declared field types do instead.
+pub fn create_sync_impl(info: &StructInfo) -> TokenStream {
+    let has_cell = info.fields.iter().any(|field| {
+        field.field_type == FieldType::BorrowedMut
+            && matches!(field.storage, FieldStorage::Inline | FieldStorage::Block)
+    });
+    if !has_cell {
+        return quote! {};
+    }
+    let mut types: Vec<_> = info
+        .fields
+        .iter()
+        .map(|field| {
+            let ty = &field.typ;
+            quote! { #ty }
+        })
+        .collect();
+    if let Some(allocator) = &info.allocator {
+        types.push(quote! { #allocator });
+    }
+    for (ty, _ident) in info.generic_consumers() {
+        types.push(quote! { ::core::marker::PhantomData<#ty> });
+    }
+    // Every bound mentions the higher-ranked 'this, so that a field which is never Sync makes the
+    // struct !Sync instead of being rejected as a trivially false bound.
+    let bounds = types.iter().map(|ty| {
+        quote! {
+            for<'this> (#ty, ::core::marker::PhantomData<&'this ()>): ::core::marker::Sync
+        }
+    });
+    let generic_params = info.generic_params();
+    let generic_args = info.generic_arguments();
+    let predicates = info
+        .generics
+        .where_clause
+        .as_ref()
+        .map(|clause| &clause.predicates);
+    let ident = &info.ident;
+    quote! {
+        // The cells are only accessed through `&mut self` or by the builders of the fields
+        // borrowing them, so sharing the struct is as safe as sharing the fields it declares.
+        unsafe impl <#generic_params> ::core::marker::Sync for #ident <#(#generic_args),*>
+        where #(#bounds,)* #predicates {}
+    }
+}
+
 /// Creates a struct with fields like the original struct. Instances of the
 /// "actual" struct are reinterpreted as instances of the "internal" struct
 /// whenever data needs to be accessed. (This gets around the problem that
@@ -36,22 +86,41 @@ pub fn create_internal_struct_def(info: &StructInfo) -> Result<TokenStream, Erro
     let ident = &info.internal_ident;
     let generics = &info.generics;
 
-    let field_defs: Vec<_> = info
+    let mut field_defs: Vec<_> = info
         .fields
         .iter()
         // Reverse the order of all fields. We ensure that items in the struct are only dependent
         // on references to items above them. Rust drops items in a struct in forward declaration order.
         // This would cause parents being dropped before children, necessitating the reversal.
         .rev()
+        .filter(|field| field.storage != FieldStorage::Block)
         .map(|field| {
             let name = &field.name;
-            let ty = field.stored_type();
+            let ty = field.stored_type(info);
             quote! {
                 #[doc(hidden)]
                 #name: #ty
             }
         })
         .collect();
+    if info.single_allocation {
+        // Declared after all other fields so that it is dropped after everything borrowing it.
+        let block_ident = info.block_ident();
+        let generic_args = info.generic_arguments();
+        field_defs.push(quote! {
+            _block: ::ouroboros::macro_help::AliasableBlock<#block_ident<#(#generic_args),*>>
+        });
+    }
+    // Generic parameters do not have to be used by any field, E.G. when the struct holds one
+    // variant of a self-referencing enum.
+    for (ty, ident) in info.generic_consumers() {
+        field_defs.push(quote! { #ident: ::core::marker::PhantomData<#ty> });
+    }
+    if info.pinned {
+        // Borrowed fields are stored inline, so the struct must never move. Being !Unpin also
+        // keeps mutable references to it from asserting exclusive access to the borrowed fields.
+        field_defs.push(quote! { _phantom_pinned: ::core::marker::PhantomPinned });
+    }
 
     // Create the new struct definition.
     let mut where_clause = quote! {};
@@ -70,3 +139,35 @@ pub fn create_internal_struct_def(info: &StructInfo) -> Result<TokenStream, Erro
 
     Ok(def)
 }
+
+/// Creates the struct holding all borrowed fields of a `single_allocation` struct, which is
+/// allocated once and then filled in one field at a time by the constructors.
+pub fn create_block_struct_def(info: &StructInfo) -> Result<TokenStream, Error> {
+    if !info.single_allocation {
+        return Ok(quote! {});
+    }
+    let ident = info.block_ident();
+    let generics = &info.generics;
+    // Reversed for the same reason as the fields of the internal struct.
+    let mut field_defs: Vec<_> = info
+        .fields
+        .iter()
+        .rev()
+        .filter(|field| field.storage == FieldStorage::Block)
+        .map(|field| {
+            let name = &field.name;
+            let ty = field.stored_type(info);
+            quote! { #name: #ty }
+        })
+        .collect();
+    for (ty, ident) in info.generic_consumers() {
+        field_defs.push(quote! { #ident: ::core::marker::PhantomData<#ty> });
+    }
+    let where_clause = &generics.where_clause;
+    let def = quote! {
+        struct #ident #generics #where_clause {
+            #(#field_defs),*
+        }
+    };
+    Ok(replace_this_with_lifetime(def, info.fake_lifetime()))
+}
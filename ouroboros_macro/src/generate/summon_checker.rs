@@ -2,7 +2,7 @@ use proc_macro2::TokenStream;
 use quote::quote;
 use syn::Error;
 
-use crate::info_structures::{ArgType, BuilderType, StructInfo};
+use crate::info_structures::{ArgType, BuilderType, FieldStorage, StructInfo};
 
 pub fn generate_checker_summoner(info: &StructInfo) -> Result<TokenStream, Error> {
     let mut code: Vec<TokenStream> = Vec::new();
@@ -11,27 +11,51 @@ pub fn generate_checker_summoner(info: &StructInfo) -> Result<TokenStream, Error
     let mut template_consumers: Vec<TokenStream> = Vec::new();
     for field in &info.fields {
         let field_name = &field.name;
-
-        let arg_type = field.make_constructor_arg_type(info, BuilderType::Sync)?;
-        if let ArgType::Plain(plain_type) = arg_type {
-            // No fancy builder function, we can just move the value directly into the struct.
-            params.push(quote! { #field_name: #plain_type });
-        } else if let ArgType::TraitBound(bound_type) = arg_type {
-            // Trait bounds are much trickier. We need a special syntax to accept them in the
-            // constructor, and generic parameters need to be added to the builder struct to make
-            // it work.
-            let builder_name = field.builder_name();
-            params.push(quote! { #builder_name : impl #bound_type });
+        // Consumed fields are passed in the order they were declared in, like in constructors.
+        for param_field in info.consumed_before(field).into_iter().chain(Some(field)) {
+            let param_name = &param_field.name;
+            let arg_type = param_field.make_constructor_arg_type(info, BuilderType::Sync)?;
+            if let ArgType::Plain(plain_type) = arg_type {
+                // No fancy builder function, we can just move the value directly into the struct.
+                params.push(quote! { #param_name: #plain_type });
+            } else if let ArgType::TraitBound(bound_type) = arg_type {
+                // Trait bounds are much trickier. We need a special syntax to accept them in the
+                // constructor, and generic parameters need to be added to the builder struct to make
+                // it work.
+                let builder_name = param_field.builder_name();
+                params.push(quote! { #builder_name : impl #bound_type });
+            }
+        }
+        // Consumed fields with builders are built right before the field consuming them.
+        for built in field
+            .consumes
+            .iter()
+            .filter(|consumed| !consumed.borrows.is_empty())
+            .chain(Some(field).filter(|field| !field.borrows.is_empty()))
+        {
+            let built_name = &built.name;
+            let builder_name = built.builder_name();
             let mut builder_args = Vec::new();
-            for (_, borrow) in field.borrows.iter().enumerate() {
-                let borrowed_name = &info.fields[borrow.index].name;
+            for borrow in built.borrows.iter() {
+                let borrowed = &info.fields[borrow.index];
+                let borrowed_name = &borrowed.name;
+                // Fields marked #[stable_deref] are borrowed through their contents.
+                let deref = if let FieldStorage::StableDeref(_) = borrowed.storage {
+                    quote! { * }
+                } else {
+                    quote! {}
+                };
                 if borrow.mutable {
-                    builder_args.push(quote! { &mut #borrowed_name });
+                    builder_args.push(quote! { &mut #deref #borrowed_name });
                 } else {
-                    builder_args.push(quote! { &#borrowed_name });
+                    builder_args.push(quote! { &#deref #borrowed_name });
                 }
             }
-            code.push(quote! { let #field_name = #builder_name (#(#builder_args),*); });
+            for consumed in &built.consumes {
+                let consumed_name = &consumed.name;
+                builder_args.push(quote! { #consumed_name });
+            }
+            code.push(quote! { let #built_name = #builder_name (#(#builder_args),*); });
         }
         if field.is_mutably_borrowed() {
             code.push(quote! { let mut #field_name = #field_name; });
@@ -1,6 +1,5 @@
-use crate::{
-    info_structures::{ArgType, BuilderType, FieldType, Options, StructInfo},
-    utils::to_class_case,
+use crate::info_structures::{
+    ArgType, BuilderType, FieldStorage, FieldType, Options, StructFieldInfo, StructInfo,
 };
 use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
@@ -8,7 +7,7 @@ use syn::Error;
 
 pub fn create_try_builder_and_constructor(
     info: &StructInfo,
-    options: Options,
+    options: &Options,
     builder_type: BuilderType,
 ) -> Result<(Ident, TokenStream, TokenStream), Error> {
     let struct_name = info.ident.clone();
@@ -30,6 +29,28 @@ pub fn create_try_builder_and_constructor(
         head_recover_code.push(quote! { #ident: ::core::marker::PhantomData });
     }
     let mut current_head_index = 0;
+    // build_cancellable() stores all heads up front and keeps them in a guard, which recovers
+    // them the same way head_recover_code does once every head has been stored.
+    let cancellable = builder_type.is_async() && !info.pinned && !info.single_allocation;
+    let mut guarded_heads = Vec::new();
+    let mut guarded_head_recover_code = Vec::new();
+    for field in &info.fields {
+        if !field.self_referencing {
+            let field_name = &field.name;
+            guarded_heads.push(field_name);
+            if field.is_borrowed() {
+                let value = field.unstore(quote! { #field_name });
+                guarded_head_recover_code.push(quote! { #field_name: #value });
+            } else {
+                guarded_head_recover_code.push(quote! { #field_name });
+            }
+        }
+    }
+    for (_ty, ident) in info.generic_consumers() {
+        guarded_head_recover_code.push(quote! { #ident: ::core::marker::PhantomData });
+    }
+    let mut cancellable_head_code: Vec<TokenStream> = Vec::new();
+    let mut cancellable_code: Vec<TokenStream> = Vec::new();
 
     let builder_struct_name = match builder_type {
         BuilderType::AsyncSend => format_ident!("{}AsyncSendTryBuilder", info.ident),
@@ -92,92 +113,194 @@ pub fn create_try_builder_and_constructor(
     let mut builder_struct_generic_consumers = info.generic_arguments();
     let mut builder_struct_fields = Vec::new();
     let mut builder_struct_field_names = Vec::new();
+    let mut param_names = Vec::new();
+    let is_tuple = info.is_tuple();
+    let internal_ident = &info.internal_ident;
+    let this_ptr = if builder_type.is_async() {
+        // Raw pointers are not Send, so they cannot be held across await points.
+        quote! { slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>() }
+    } else {
+        quote! { this }
+    };
+    let block_ptr = quote! { block_slot.as_ptr() };
+    // Stored fields are taken out of place by hand if a builder fails.
+    let disarm_drop_guard = if info.pinned || info.single_allocation {
+        quote! { drop_guard.disarm(); }
+    } else {
+        quote! {}
+    };
+    // Fields of pinned structs and fields in the block of single_allocation structs which have
+    // already been moved into place, and have to be taken out again if a builder fails.
+    let mut stored_fields = Vec::new();
+
+    // The calls to the builders of consumed fields, which are made right before the builder of
+    // the field consuming them.
+    let mut consumed_builder_values = Vec::new();
 
     for field in &info.fields {
         let field_name = &field.name;
 
-        let arg_type = field.make_try_constructor_arg_type(info, builder_type)?;
-        if let ArgType::Plain(plain_type) = arg_type {
-            // No fancy builder function, we can just move the value directly into the struct.
-            params.push(quote! { #field_name: #plain_type });
-            builder_struct_fields.push(quote! { #field_name: #plain_type });
-            builder_struct_field_names.push(quote! { #field_name });
-            doc_table += &format!(
-                "| `{}` | Directly pass in the value this field should contain |\n",
-                field_name
-            );
-            if !field.self_referencing {
-                if field.is_borrowed() {
-                    head_recover_code[current_head_index] = quote! {
-                        #field_name: ::ouroboros::macro_help::unbox(#field_name)
-                    };
+        let mut builder_values = Vec::new();
+        // Consumed fields are not stored, but constructors still take them in the order they
+        // were declared in.
+        for param_field in info.consumed_before(field).into_iter().chain(Some(field)) {
+            let param_name = &param_field.name;
+            let moved_into = if param_field.declaration_index == field.declaration_index {
+                String::new()
+            } else {
+                format!(
+                    ", which is moved into the builder of `{}`",
+                    info.consumer_of(param_field).accessor_name()
+                )
+            };
+            let arg_type = param_field.make_try_constructor_arg_type(info, builder_type)?;
+            if let ArgType::Plain(plain_type) = arg_type {
+                // No fancy builder function, we can just move the value directly into the struct.
+                params.push(quote! { #param_name: #plain_type });
+                param_names.push(quote! { #param_name });
+                if is_tuple {
+                    builder_struct_fields.push(quote! { #plain_type });
                 } else {
-                    head_recover_code[current_head_index] = quote! { #field_name };
+                    builder_struct_fields.push(quote! { #param_name: #plain_type });
                 }
-                current_head_index += 1;
-            }
-        } else if let ArgType::TraitBound(bound_type) = arg_type {
-            // Trait bounds are much trickier. We need a special syntax to accept them in the
-            // constructor, and generic parameters need to be added to the builder struct to make
-            // it work.
-            let builder_name = field.builder_name();
-            params.push(quote! { #builder_name : impl #bound_type });
-            // Ok so hear me out basically without this thing here my IDE thinks the rest of the
-            // code is a string and it all turns green.
-            {}
-            doc_table += &format!(
-                "| `{}` | Use a function or closure: `(",
-                builder_name
-            );
-            let mut builder_args = Vec::new();
-            for (index, borrow) in field.borrows.iter().enumerate() {
-                let borrowed_name = &info.fields[borrow.index].name;
-                builder_args.push(format_ident!("{}_illegal_static_reference", borrowed_name));
+                builder_struct_field_names.push(param_field.builder_member());
                 doc_table += &format!(
-                    "{}: &{}_",
-                    borrowed_name,
-                    if borrow.mutable { "mut " } else { "" },
+                    "| `{}` | Directly pass in the value this field should contain{} |\n",
+                    param_field.accessor_name(),
+                    moved_into
                 );
-                if index < field.borrows.len() - 1 {
-                    doc_table += ", ";
+                if !field.self_referencing && moved_into.is_empty() {
+                    if field.is_borrowed() || info.pinned {
+                        let value = field.unstore(quote! { #field_name });
+                        head_recover_code[current_head_index] = quote! { #field_name: #value };
+                    } else {
+                        head_recover_code[current_head_index] = quote! { #field_name };
+                    }
+                    current_head_index += 1;
                 }
+            } else if let ArgType::TraitBound(bound_type) = arg_type {
+                // Trait bounds are much trickier. We need a special syntax to accept them in the
+                // constructor, and generic parameters need to be added to the builder struct to make
+                // it work.
+                let builder_name = param_field.builder_name();
+                params.push(quote! { #builder_name : impl #bound_type });
+                param_names.push(quote! { #builder_name });
+                // Ok so hear me out basically without this thing here my IDE thinks the rest of the
+                // code is a string and it all turns green.
+                {}
+                doc_table += &format!(
+                    "| `{}` | Use a function or closure: `({}) -> Result<{}: _, Error_>`{} | \n",
+                    if is_tuple {
+                        param_field.accessor_name()
+                    } else {
+                        builder_name.to_string()
+                    },
+                    param_field.builder_doc_params(info),
+                    param_field.accessor_name(),
+                    moved_into
+                );
+                let builder_args = param_field.builder_args(info);
+                let builder_value = if builder_type.is_async() {
+                    quote! { #builder_name (#(#builder_args),*).await }
+                } else {
+                    quote! { #builder_name (#(#builder_args),*) }
+                };
+                if moved_into.is_empty() {
+                    builder_values.push((param_name, builder_value));
+                } else {
+                    consumed_builder_values.push((param_name, builder_value));
+                }
+                let generic_type_name = param_field.builder_generic_name();
+
+                builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
+                builder_struct_generic_consumers.push(quote! { #generic_type_name });
+                if is_tuple {
+                    builder_struct_fields.push(quote! { #generic_type_name });
+                } else {
+                    builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
+                }
+                builder_struct_field_names.push(param_field.builder_member());
             }
-            doc_table += &format!(") -> Result<{}: _, Error_>` | \n", field_name);
-            let builder_value = if builder_type.is_async() {
-                quote! { #builder_name (#(#builder_args),*).await }
-            } else {
-                quote! { #builder_name (#(#builder_args),*) }
-            };
+        }
+        // Consumed fields with builders are built right before the field consuming them.
+        for consumed in field.consumes.iter().rev() {
+            let position = consumed_builder_values
+                .iter()
+                .position(|(name, _)| **name == consumed.name);
+            if let Some(position) = position {
+                builder_values.insert(0, consumed_builder_values.remove(position));
+            }
+        }
+        for (value_name, builder_value) in builder_values {
+            let take_stored_fields = stored_fields.iter().rev().map(|field: &&StructFieldInfo| {
+                if field.storage == FieldStorage::Block {
+                    field.take_from_place(&block_ptr, !field.self_referencing)
+                } else {
+                    field.take_from_place(&this_ptr, !field.self_referencing)
+                }
+            });
             or_recover_code.push(quote! {
-                let #field_name = match #builder_value {
+                let #value_name = match #builder_value {
                     ::core::result::Result::Ok(value) => value,
-                    ::core::result::Result::Err(err)
-                        => return ::core::result::Result::Err((err, Heads { #(#head_recover_code),* })),
+                    ::core::result::Result::Err(err) => {
+                        #disarm_drop_guard
+                        #(#take_stored_fields)*
+                        return ::core::result::Result::Err((err, Heads { #(#head_recover_code),* }));
+                    }
                 };
             });
-            let generic_type_name =
-                format_ident!("{}Builder_", to_class_case(field_name.to_string().as_str()));
-
-            builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
-            builder_struct_generic_consumers.push(quote! { #generic_type_name });
-            builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
-            builder_struct_field_names.push(quote! { #builder_name });
+            cancellable_code.push(quote! {
+                let #value_name = match #builder_value {
+                    ::core::result::Result::Ok(value) => value,
+                    ::core::result::Result::Err(err) => {
+                        let (#(#guarded_heads,)*) = guard.disarm();
+                        return ::core::result::Result::Err((err, Heads { #(#guarded_head_recover_code),* }));
+                    }
+                };
+            });
+        }
+        if field.storage == FieldStorage::Block {
+            or_recover_code.push(field.store_in_place(&block_ptr));
+            or_recover_code.push(quote! { drop_guard.push(); });
+            stored_fields.push(field);
+            continue;
         }
+        if info.pinned {
+            or_recover_code.push(field.store_in_place(&this_ptr));
+            or_recover_code.push(quote! { drop_guard.push(); });
+            stored_fields.push(field);
+            continue;
+        }
+        let mut store_code = Vec::new();
         if field.is_borrowed() {
             let boxed = field.boxed();
             if field.field_type == FieldType::BorrowedMut {
-                or_recover_code.push(quote! { let mut #field_name = #boxed; });
+                store_code.push(quote! { let mut #field_name = #boxed; });
             } else {
-                or_recover_code.push(quote! { let #field_name = #boxed; });
+                store_code.push(quote! { let #field_name = #boxed; });
             }
         }
 
         if field.field_type == FieldType::Borrowed {
-            or_recover_code.push(field.make_illegal_static_reference());
+            store_code.push(field.make_illegal_static_reference());
         } else if field.field_type == FieldType::BorrowedMut {
-            or_recover_code.push(field.make_illegal_static_mut_reference());
+            store_code.push(field.make_illegal_static_mut_reference());
+        }
+        or_recover_code.extend(store_code.iter().cloned());
+        if field.self_referencing {
+            cancellable_code.extend(store_code);
+        } else {
+            cancellable_head_code.extend(store_code);
         }
     }
+    if let Some((param, builder_field, builder_member)) = info.allocator_arg() {
+        params.push(param);
+        builder_struct_fields.push(builder_field);
+        builder_struct_field_names.push(builder_member);
+        param_names.push(quote! { allocator });
+        doc_table += "| `allocator` | The allocator used to box borrowed fields |\n";
+    }
+
     let documentation = if !options.do_no_doc {
         let documentation = documentation + &doc_table;
         quote! {
@@ -218,19 +341,63 @@ pub fn create_try_builder_and_constructor(
         BuilderType::Sync => quote! { fn try_new },
     };
     let constructor_code = if builder_type.is_async() {
-        quote! { #struct_name::#or_recover_ident(#(#builder_struct_field_names),*).await.map_err(|(error, _heads)| error) }
+        quote! { #struct_name::#or_recover_ident(#(#param_names),*).await.map_err(|(error, _heads)| error) }
     } else {
-        quote! { #struct_name::#or_recover_ident(#(#builder_struct_field_names),*).map_err(|(error, _heads)| error) }
+        quote! { #struct_name::#or_recover_ident(#(#param_names),*).map_err(|(error, _heads)| error) }
     };
-    let field_names: Vec<_> = info.fields.iter().map(|field| field.name.clone()).collect();
-    let internal_ident = &info.internal_ident;
-    let constructor_def = quote! {
-        #documentation
-        #visibility #constructor_fn<Error_>(#(#params),*) -> ::core::result::Result<#struct_name <#(#generic_args),*>, Error_> {
-            #constructor_code
+    let mut field_names: Vec<_> = info
+        .fields
+        .iter()
+        .filter(|field| field.storage != FieldStorage::Block)
+        .map(|field| {
+            let name = &field.name;
+            quote! { #name }
+        })
+        .collect();
+    if info.single_allocation {
+        field_names.push(quote! { _block: block_slot.assume_init_block() });
+    }
+    for (_ty, ident) in info.generic_consumers() {
+        field_names.push(quote! { #ident: ::core::marker::PhantomData });
+    }
+    let constructed_type = info.constructed_type();
+    let block_slot_def = info.make_block_slot();
+    let or_recover_body = if info.pinned {
+        let this_def = if builder_type.is_async() {
+            quote! {}
+        } else {
+            quote! { let this = slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>(); }
+        };
+        let drop_guard_def = info.make_drop_guard(
+            &quote! { slot.as_ptr().cast::<#internal_ident<#(#generic_args),*>>() },
+            &stored_fields,
+        );
+        quote! {
+            let slot = ::ouroboros::macro_help::UninitBox::<Self>::new();
+            #this_def
+            #drop_guard_def
+            #(#or_recover_code)*
+            drop_guard.disarm();
+            // The remaining fields are zero-sized markers which need no initialization.
+            ::core::result::Result::Ok(unsafe { slot.assume_init() })
         }
-        #or_recover_documentation
-        #visibility #or_recover_constructor_fn<Error_>(#(#params),*) -> ::core::result::Result<#struct_name <#(#generic_args),*>, (Error_, Heads<#(#generic_args),*>)> {
+    } else if info.single_allocation {
+        let drop_guard_def = info.make_drop_guard(&block_ptr, &stored_fields);
+        quote! {
+            #block_slot_def
+            #drop_guard_def
+            #(#or_recover_code)*
+            drop_guard.disarm();
+            ::core::result::Result::Ok(unsafe {
+                Self {
+                    actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
+                        #(#field_names),*
+                    })
+                }
+            })
+        }
+    } else {
+        quote! {
             #(#or_recover_code)*
             ::core::result::Result::Ok(unsafe {
                 Self {
@@ -241,6 +408,63 @@ pub fn create_try_builder_and_constructor(
             })
         }
     };
+    let mut constructor_def = quote! {
+        #documentation
+        #visibility #constructor_fn<Error_>(#(#params),*) -> ::core::result::Result<#constructed_type, Error_> {
+            #constructor_code
+        }
+        #or_recover_documentation
+        #visibility #or_recover_constructor_fn<Error_>(#(#params),*) -> ::core::result::Result<#constructed_type, (Error_, Heads<#(#generic_args),*>)> {
+            #or_recover_body
+        }
+    };
+    let cancellable_ident = match builder_type {
+        BuilderType::AsyncSend => quote! { try_new_cancellable_async_send },
+        _ => quote! { try_new_cancellable_async },
+    };
+    if cancellable {
+        let cancellable_documentation = if !options.do_no_doc {
+            let cancellable_documentation = format!(
+                concat!(
+                    "(See also [`{0}::build_cancellable()`]({0}::build_cancellable).) Like ",
+                    "[`{1}`](Self::{1}), but all ",
+                    "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) ",
+                    "are passed to `on_cancel` if the returned future is dropped before it ",
+                    "completes. The arguments are as follows:\n\n",
+                    "| Argument | Suggested Use |\n| --- | --- |\n",
+                ),
+                builder_struct_name, or_recover_ident
+            ) + &doc_table
+                + "| `on_cancel` | Use a function or closure: `(heads: Heads) -> ()` |\n";
+            quote! { #[doc=#cancellable_documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        constructor_def.extend(quote! {
+            #cancellable_documentation
+            #visibility async fn #cancellable_ident<Error_>(
+                #(#params,)*
+                on_cancel: impl ::core::ops::FnOnce(Heads<#(#generic_args),*>),
+            ) -> ::core::result::Result<#constructed_type, (Error_, Heads<#(#generic_args),*>)> {
+                #(#cancellable_head_code)*
+                // Builders and the fields they built are dropped before the guard, so the heads
+                // are no longer borrowed when they are passed to on_cancel.
+                let guard = ::ouroboros::macro_help::CancelGuard::new(
+                    (#(#guarded_heads,)*),
+                    move |(#(#guarded_heads,)*)| on_cancel(Heads { #(#guarded_head_recover_code),* }),
+                );
+                #(#cancellable_code)*
+                let (#(#guarded_heads,)*) = guard.disarm();
+                ::core::result::Result::Ok(unsafe {
+                    Self {
+                        actual_data: ::core::mem::MaybeUninit::new(#internal_ident {
+                            #(#field_names),*
+                        })
+                    }
+                })
+            }
+        });
+    }
     builder_struct_generic_producers.push(quote! { Error_ });
     builder_struct_generic_consumers.push(quote! { Error_ });
     let generic_where = &info.generics.where_clause;
@@ -288,20 +512,56 @@ pub fn create_try_builder_and_constructor(
             )
         },
     };
+    let build_cancellable_fn = if cancellable {
+        let build_cancellable_fn_documentation = format!(
+            concat!(
+                "Calls [`{0}::{1}()`]({0}::{1}) using the provided values. If the returned ",
+                "future is dropped before it completes, the head fields which were moved into ",
+                "it are passed to `on_cancel` instead of being dropped. "
+            ),
+            info.ident, cancellable_ident
+        );
+        quote! {
+            #[doc=#build_cancellable_fn_documentation]
+            #visibility async fn build_cancellable(
+                self,
+                on_cancel: impl ::core::ops::FnOnce(Heads<#(#generic_args),*>),
+            ) -> ::core::result::Result<#constructed_type, (Error_, Heads<#(#generic_args),*>)> {
+                #struct_name::#cancellable_ident(
+                    #(self.#builder_struct_field_names,)*
+                    on_cancel,
+                ).await
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let builder_struct_def = if is_tuple {
+        quote! {
+            #visibility struct #builder_struct_name <#(#builder_struct_generic_producers),*> (
+                #(#visibility #builder_struct_fields),*
+            ) #generic_where;
+        }
+    } else {
+        quote! {
+            #visibility struct #builder_struct_name <#(#builder_struct_generic_producers),*> #generic_where {
+                #(#visibility #builder_struct_fields),*
+            }
+        }
+    };
     let builder_def = quote! {
         #builder_documentation
-        #visibility struct #builder_struct_name <#(#builder_struct_generic_producers),*> #generic_where {
-            #(#visibility #builder_struct_fields),*
-        }
+        #builder_struct_def
         impl<#(#builder_struct_generic_producers),*> #builder_struct_name <#(#builder_struct_generic_consumers),*> #generic_where {
             #[doc=#build_fn_documentation]
-            #visibility #builder_fn(self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, Error_> {
+            #visibility #builder_fn(self) -> ::core::result::Result<#constructed_type, Error_> {
                 #builder_code
             }
             #[doc=#build_or_recover_fn_documentation]
-            #visibility #or_recover_builder_fn(self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, (Error_, Heads<#(#generic_args),*>)> {
+            #visibility #or_recover_builder_fn(self) -> ::core::result::Result<#constructed_type, (Error_, Heads<#(#generic_args),*>)> {
                 #or_recover_builder_code
             }
+            #build_cancellable_fn
         }
     };
     Ok((builder_struct_name, builder_def, constructor_def))
@@ -1,9 +1,10 @@
 use proc_macro2::TokenStream;
-use quote::{format_ident, quote};
-use syn::GenericParam;
+use quote::{format_ident, quote, quote_spanned};
+use syn::{spanned::Spanned, GenericParam};
 
 use crate::{
-    covariance_detection::apparent_std_container_type, info_structures::StructInfo,
+    covariance_detection::apparent_std_container_type,
+    info_structures::{FieldStorage, Options, StableDerefType, StructInfo},
     utils::replace_this_with_lifetime,
 };
 
@@ -30,6 +31,18 @@ pub fn make_type_asserts(info: &StructInfo) -> TokenStream {
                 ::ouroboros::macro_help::CheckIfTypeIsStd::<#static_field_type>::#checker_name();
             });
         }
+        // Box, Arc and Rc are already checked above.
+        let checker_name = match field.storage {
+            FieldStorage::StableDeref(StableDerefType::Vec) => "is_std_vec_type",
+            FieldStorage::StableDeref(StableDerefType::String) => "is_std_string_type",
+            _ => continue,
+        };
+        let checker_name = format_ident!("{}", checker_name);
+        let static_field_type =
+            replace_this_with_lifetime(quote! { #field_type }, fake_lifetime.clone());
+        checks.push(quote! {
+            ::ouroboros::macro_help::CheckIfTypeIsStd::<#static_field_type>::#checker_name();
+        });
     }
     let generic_params = info.generic_params();
     let generic_where = &info.generics.where_clause;
@@ -39,3 +52,63 @@ pub fn make_type_asserts(info: &StructInfo) -> TokenStream {
         }
     }
 }
+
+/// Checks that every field of the internal struct is `Send` or `Sync` when `assert_send` or
+/// `assert_sync` is used, so that errors point at the field which keeps the struct from
+/// implementing them instead of wherever the struct is used.
+pub fn make_auto_trait_asserts(info: &StructInfo, options: &Options) -> TokenStream {
+    let mut traits = Vec::new();
+    if options.do_assert_send {
+        traits.push(quote! { ::core::marker::Send });
+    }
+    if options.do_assert_sync {
+        traits.push(quote! { ::core::marker::Sync });
+    }
+    if traits.is_empty() {
+        return quote! {};
+    }
+    let fake_lifetime = info.fake_lifetime();
+    let mut stored_types = Vec::new();
+    for field in &info.fields {
+        let span = field.typ.span();
+        let field_type = &field.typ;
+        // The boxes and cells borrowed fields are stored in implement Send and Sync like their
+        // contents.
+        let stored_type = replace_this_with_lifetime(quote! { #field_type }, fake_lifetime.clone());
+        stored_types.push((span, stored_type));
+    }
+    if let Some(allocator) = &info.allocator {
+        stored_types.push((allocator.span(), quote! { #allocator }));
+    }
+    for (ty, _ident) in info.generic_consumers() {
+        let span = ty.span();
+        stored_types.push((
+            span,
+            quote_spanned! { span=> ::core::marker::PhantomData<#ty> },
+        ));
+    }
+    if info.generic_params().is_empty() {
+        let checks = stored_types.iter().map(|(span, stored_type)| {
+            quote_spanned! { *span=>
+                ::ouroboros::macro_help::assert_impl_all!(#stored_type: #(#traits),*);
+            }
+        });
+        return quote! { #(#checks)* };
+    }
+    // assert_impl_all!() cannot refer to generic parameters, so the check is done inside of a
+    // function which has the same bounds as the struct.
+    let checks = stored_types.iter().map(|(span, stored_type)| {
+        quote_spanned! { *span=>
+            assert_impl_all::<#stored_type>();
+        }
+    });
+    let generic_params = info.generic_params();
+    let generic_where = &info.generics.where_clause;
+    quote! {
+        #[allow(dead_code)]
+        fn auto_trait_asserts <#generic_params>() #generic_where {
+            fn assert_impl_all<T: ?::core::marker::Sized #(+ #traits)*>() {}
+            #(#checks)*
+        }
+    }
+}
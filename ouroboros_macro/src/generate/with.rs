@@ -5,7 +5,7 @@ use syn::{Error, Lifetime, WhereClause};
 
 pub fn make_with_all_function(
     info: &StructInfo,
-    options: Options,
+    options: &Options,
 ) -> Result<(TokenStream, TokenStream), Error> {
     let visibility = if options.do_pub_extras {
         info.vis.clone()
@@ -22,11 +22,13 @@ pub fn make_with_all_function(
             fields.push(quote! { #visibility #field_name: &'outer_borrow #field_type });
             field_assignments.push(quote! { #field_name: &this.#field_name });
         } else if field.field_type == FieldType::Borrowed {
+            let borrowed = field.borrow_stored(quote! { this });
             let ass = quote! { #field_name: unsafe {
                 ::ouroboros::macro_help::change_lifetime(
-                    &*this.#field_name
+                    #borrowed
                 )
             } };
+            let field_type = field.borrowed_type();
             fields.push(quote! { #visibility #field_name: &'this #field_type });
             field_assignments.push(ass.clone());
         } else if field.field_type == FieldType::BorrowedMut {
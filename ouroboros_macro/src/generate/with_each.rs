@@ -2,14 +2,11 @@ use crate::info_structures::{FieldType, Options, StructInfo};
 use proc_macro2::TokenStream;
 use proc_macro2_diagnostics::Diagnostic;
 use quote::{format_ident, quote};
-use syn::Error;
 
-pub enum ProcessingError {
-    Syntax(Error),
-    Covariance(Vec<Diagnostic>),
-}
-
-pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStream>, Vec<Diagnostic>) {
+pub fn make_with_functions(
+    info: &StructInfo,
+    options: &Options,
+) -> (Vec<TokenStream>, Vec<Diagnostic>) {
     let mut users = Vec::new();
     let mut errors = Vec::new();
     for field in &info.fields {
@@ -19,13 +16,13 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
         // If the field is not a tail, we need to serve up the same kind of reference that other
         // fields in the struct may have borrowed to ensure safety.
         if field.field_type == FieldType::Tail {
-            let user_name = format_ident!("with_{}", &field.name);
+            let user_name = format_ident!("with_{}", field.accessor_name());
             let documentation = format!(
                 concat!(
                     "Provides an immutable reference to `{0}`. This method was generated because ",
                     "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions)."
                 ),
-                field.name.to_string()
+                field.accessor_name()
             );
             let documentation = if !options.do_no_doc {
                 quote! {
@@ -46,10 +43,11 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
                 }
             });
             if field.covariant == Some(true) {
-                let borrower_name = format_ident!("borrow_{}", &field.name);
+                let borrower_name = format_ident!("borrow_{}", field.accessor_name());
                 users.push(quote! {
                     #documentation
                     #[inline(always)]
+                    #[allow(clippy::mut_from_ref)]
                     #visibility fn #borrower_name<'this>(
                         &'this self,
                     ) -> &'this #field_type {
@@ -60,16 +58,28 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
                 errors.push(field.covariance_error());
             }
             // If it is not borrowed at all it's safe to allow mutably borrowing it.
-            let user_name = format_ident!("with_{}_mut", &field.name);
-            let documentation = format!(
-                concat!(
-                    "Provides a mutable reference to `{0}`. This method was generated because ",
-                    "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions). ",
-                    "No `borrow_{0}_mut` function was generated because Rust's borrow checker is ",
-                    "currently unable to guarantee that such a method would be used safely."
-                ),
-                field.name.to_string()
-            );
+            let user_name = format_ident!("with_{}_mut", field.accessor_name());
+            let documentation = if field.self_referencing {
+                format!(
+                    concat!(
+                        "Provides a mutable reference to `{0}`. This method was generated because ",
+                        "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions). ",
+                        "No `borrow_{0}_mut` function was generated because `{0}` borrows other ",
+                        "fields: a plain or guarded `&mut` would let a reference which lives shorter ",
+                        "than the struct be assigned to `{0}`, leaving it dangling once that reference ",
+                        "expires. Only the closure passed to this method can prevent that."
+                    ),
+                    field.accessor_name()
+                )
+            } else {
+                format!(
+                    concat!(
+                        "Provides a mutable reference to `{0}`. This method was generated because ",
+                        "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions)."
+                    ),
+                    field.accessor_name()
+                )
+            };
             let documentation = if !options.do_no_doc {
                 quote! {
                     #[doc=#documentation]
@@ -77,25 +87,59 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
             } else {
                 quote! { #[doc(hidden)] }
             };
+            let self_param = info.mut_self_param();
+            let internal_mut = info.internal_mut();
             users.push(quote! {
                 #documentation
                 #[inline(always)]
                 #visibility fn #user_name <'outer_borrow, ReturnType>(
-                    &'outer_borrow mut self,
+                    #self_param,
                     user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow mut #field_type) -> ReturnType,
                 ) -> ReturnType {
-                    let field = &mut unsafe { self.actual_data.assume_init_mut() }.#field_name;
+                    let field = &mut #internal_mut.#field_name;
                     user(field)
                 }
             });
+            // Fields which do not borrow anything cannot contain references to other fields, so
+            // nothing can be assigned to them which would make the struct unsound.
+            if !field.self_referencing {
+                let borrower_name = format_ident!("borrow_{}_mut", field.accessor_name());
+                let documentation = format!(
+                    concat!(
+                        "Provides a mutable reference to `{0}`. This method was generated because ",
+                        "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) ",
+                        "which does not borrow any other fields."
+                    ),
+                    field.accessor_name()
+                );
+                let documentation = if !options.do_no_doc {
+                    quote! {
+                        #[doc=#documentation]
+                    }
+                } else {
+                    quote! { #[doc(hidden)] }
+                };
+                users.push(quote! {
+                    #documentation
+                    #[inline(always)]
+                    #visibility fn #borrower_name<'outer_borrow>(
+                        #self_param,
+                    ) -> &'outer_borrow mut #field_type {
+                        &mut #internal_mut.#field_name
+                    }
+                });
+            }
         } else if field.field_type == FieldType::Borrowed {
-            let user_name = format_ident!("with_{}", &field.name);
+            let borrowed =
+                field.borrow_stored(quote! { unsafe { self.actual_data.assume_init_ref() } });
+            let field_type = field.borrowed_type();
+            let user_name = format_ident!("with_{}", field.accessor_name());
             let documentation = format!(
                 concat!(
                     "Provides limited immutable access to `{0}`. This method was generated ",
                     "because the contents of `{0}` are immutably borrowed by other fields."
                 ),
-                field.name.to_string()
+                field.accessor_name()
             );
             let documentation = if !options.do_no_doc {
                 quote! {
@@ -111,7 +155,7 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
                     &'outer_borrow self,
                     user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow #field_type) -> ReturnType,
                 ) -> ReturnType {
-                    let field = &unsafe { self.actual_data.assume_init_ref() }.#field_name;
+                    let field = #borrowed;
                     user(field)
                 }
             });
@@ -123,14 +167,14 @@ pub fn make_with_functions(info: &StructInfo, options: Options) -> (Vec<TokenStr
                     errors.push(field.covariance_error());
                 }
             }
-            let borrower_name = format_ident!("borrow_{}", &field.name);
+            let borrower_name = format_ident!("borrow_{}", field.accessor_name());
             users.push(quote! {
                 #documentation
                 #[inline(always)]
                 #visibility fn #borrower_name<'this>(
                     &'this self,
                 ) -> &'this #field_type {
-                    &unsafe { self.actual_data.assume_init_ref() }.#field_name
+                    #borrowed
                 }
             });
         } else if field.field_type == FieldType::BorrowedMut {
@@ -8,7 +8,7 @@ use syn::{Error, Lifetime, WhereClause};
 
 pub fn make_with_all_mut_function(
     info: &StructInfo,
-    options: Options,
+    options: &Options,
 ) -> Result<(TokenStream, TokenStream), Error> {
     let visibility = if options.do_pub_extras {
         info.vis.clone()
@@ -23,7 +23,8 @@ pub fn make_with_all_mut_function(
         let field_name = &field.name;
         let original_field_type = &field.typ;
         let lifetime = format_ident!("this{}", index);
-        let field_type = replace_this_with_lifetime(quote! { #original_field_type }, lifetime.clone());
+        let field_type =
+            replace_this_with_lifetime(quote! { #original_field_type }, lifetime.clone());
         if field.field_type == FieldType::Tail {
             mut_fields.push(quote! { #visibility #field_name: &'outer_borrow mut #field_type });
             mut_field_assignments.push(quote! { #field_name: &mut this.#field_name });
@@ -31,11 +32,13 @@ pub fn make_with_all_mut_function(
                 lifetime_idents.push(lifetime.clone());
             }
         } else if field.field_type == FieldType::Borrowed {
+            let borrowed = field.borrow_stored(quote! { this });
             let ass = quote! { #field_name: unsafe {
                 ::ouroboros::macro_help::change_lifetime(
-                    &*this.#field_name
+                    #borrowed
                 )
             } };
+            let field_type = replace_this_with_lifetime(field.borrowed_type(), lifetime.clone());
             let lt = Lifetime::new(&format!("'{}", lifetime), Span::call_site());
             mut_fields.push(quote! { #visibility #field_name: &#lt #field_type });
             mut_field_assignments.push(ass);
@@ -83,17 +86,13 @@ pub fn make_with_all_mut_function(
     for lt in &lifetime_idents {
         let lt = Lifetime::new(&format!("'{}", lt), Span::call_site());
         let extra: WhereClause = syn::parse_quote! { where #fake_lifetime: #lt };
-        generic_where
-            .predicates
-            .extend(extra.predicates.into_iter());
+        generic_where.predicates.extend(extra.predicates);
     }
     for idents in lifetime_idents.windows(2) {
         let lt = Lifetime::new(&format!("'{}", idents[1]), Span::call_site());
         let outlives = Lifetime::new(&format!("'{}", idents[0]), Span::call_site());
         let extra: WhereClause = syn::parse_quote! { where #lt: #outlives };
-        generic_where
-            .predicates
-            .extend(extra.predicates.into_iter());
+        generic_where.predicates.extend(extra.predicates);
     }
     let struct_defs = quote! {
         #[doc=#mut_struct_documentation]
@@ -111,14 +110,16 @@ pub fn make_with_all_mut_function(
     } else {
         quote! { #[doc(hidden)] }
     };
+    let self_param = info.mut_self_param();
+    let internal_mut = info.internal_mut();
     let fn_defs = quote! {
         #mut_documentation
         #[inline(always)]
         #visibility fn with_mut <'outer_borrow, ReturnType>(
-            &'outer_borrow mut self,
+            #self_param,
             user: impl for<#(#lifetimes),*> ::core::ops::FnOnce(#borrowed_mut_fields_type) -> ReturnType
         ) -> ReturnType {
-            let this = unsafe { self.actual_data.assume_init_mut() };
+            let this = #internal_mut;
             user(BorrowedMutFields {
                 #(#mut_field_assignments),*
             })
@@ -1,16 +1,29 @@
-use crate::utils::{make_generic_arguments, make_generic_consumers, replace_this_with_lifetime};
+use crate::covariance_detection::apparent_stable_deref_type;
+use crate::utils::{
+    make_generic_arguments, make_generic_consumers, replace_this_with_lifetime, to_class_case,
+};
 use proc_macro2::{Ident, TokenStream};
 use proc_macro2_diagnostics::{Diagnostic, SpanDiagnosticExt};
 use quote::{format_ident, quote, ToTokens};
 use syn::{
-    punctuated::Punctuated, token::Comma, Attribute, ConstParam, Error, GenericParam, Generics,
-    LifetimeParam, Type, TypeParam, Visibility, spanned::Spanned, 
+    ext::IdentExt, punctuated::Punctuated, spanned::Spanned, token::Comma, Attribute, ConstParam,
+    Error, GenericParam, Generics, LifetimeParam, Path, Type, TypeParam, Visibility,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Options {
     pub do_no_doc: bool,
     pub do_pub_extras: bool,
+    pub do_pinned: bool,
+    pub do_single_allocation: bool,
+    /// Set by `stable_deref`, stores every borrowed container as if it was marked
+    /// `#[stable_deref]`.
+    pub do_stable_deref: bool,
+    /// Set by `allocator = Type`, the allocator which borrowed fields are boxed with.
+    pub allocator: Option<Type>,
+    pub do_assert_send: bool,
+    pub do_assert_sync: bool,
+    pub do_async_fn: bool,
 }
 
 impl Options {
@@ -39,6 +52,42 @@ impl FieldType {
     }
 }
 
+/// Where a borrowed field is kept so that it does not move while it is borrowed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FieldStorage {
+    /// In an `AliasableBox` owned by the struct.
+    Boxed,
+    /// In an `AliasableBoxIn` allocated with the allocator given in
+    /// `#[self_referencing(allocator = Type)]`.
+    BoxedIn,
+    /// Directly inside the struct, which is pinned for as long as it exists. Used for all fields
+    /// of `#[self_referencing(pinned)]` structs.
+    Inline,
+    /// In a block allocated once for all borrowed fields, see `StructInfo::block_ident()`. Used for
+    /// the borrowed fields of `#[self_referencing(single_allocation)]` structs.
+    Block,
+    /// Directly inside the struct, for fields marked `#[stable_deref]`. The field is a container
+    /// whose contents do not move along with it, and it is only ever borrowed through them.
+    StableDeref(StableDerefType),
+}
+
+/// The standard library containers which can be used in `#[stable_deref]` fields.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StableDerefType {
+    Box,
+    Arc,
+    Rc,
+    Vec,
+    String,
+}
+
+impl StableDerefType {
+    /// True if the contents of the container can be mutated through it.
+    pub fn is_unique(self) -> bool {
+        matches!(self, Self::Box | Self::Vec | Self::String)
+    }
+}
+
 #[derive(Clone)]
 pub struct BorrowRequest {
     pub index: usize,
@@ -50,6 +99,23 @@ pub enum Derive {
     Debug,
     PartialEq,
     Eq,
+    Clone,
+    Default,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+}
+
+/// How a field is shown by a derived `Debug` implementation.
+#[derive(Clone)]
+pub enum DebugFormat {
+    Default,
+    /// Set by `#[debug(skip)]`.
+    Skip,
+    /// Set by `#[debug(with = path::to_fn)]`, the function is used in place of `Debug::fmt`.
+    With(Path),
 }
 
 #[derive(Copy, Clone)]
@@ -75,6 +141,18 @@ pub struct StructInfo {
     pub fields: Vec<StructFieldInfo>,
     pub first_lifetime: Ident,
     pub attributes: Vec<Attribute>,
+    /// Set by `#[self_referencing(pinned)]`. Instances are only ever accessible behind a `Pin`.
+    pub pinned: bool,
+    /// Set by `#[self_referencing(single_allocation)]`. All borrowed fields are stored in one heap
+    /// allocation instead of one `AliasableBox` each.
+    pub single_allocation: bool,
+    /// Set by `#[self_referencing(allocator = Type)]`. Constructors take an additional `allocator`
+    /// argument which is cloned to box each borrowed field.
+    pub allocator: Option<Type>,
+    /// Set by `#[self_referencing(async_fn)]`. The builders of `MyStructAsyncBuilder` and
+    /// `MyStructAsyncTryBuilder` are `AsyncFnOnce` closures instead of closures returning boxed
+    /// futures.
+    pub async_fn: bool,
 }
 
 impl StructInfo {
@@ -127,11 +205,201 @@ impl StructInfo {
     pub fn generic_consumers(&self) -> impl Iterator<Item = (TokenStream, Ident)> {
         make_generic_consumers(&self.generics)
     }
+
+    /// The field whose builder `consumed` is moved into.
+    pub fn consumer_of(&self, consumed: &StructFieldInfo) -> &StructFieldInfo {
+        self.fields
+            .iter()
+            .find(|field| {
+                field
+                    .consumes
+                    .iter()
+                    .any(|other| other.declaration_index == consumed.declaration_index)
+            })
+            .expect("Consumed field has no consumer.")
+    }
+
+    /// The fields consumed by other fields which were declared between `field` and the stored field
+    /// before it, in the order they were declared in. Constructors take them before `field`.
+    pub fn consumed_before(&self, field: &StructFieldInfo) -> Vec<&StructFieldInfo> {
+        let previous = self
+            .fields
+            .iter()
+            .map(|other| other.declaration_index)
+            .filter(|index| *index < field.declaration_index)
+            .max();
+        let mut consumed: Vec<_> = self
+            .fields
+            .iter()
+            .flat_map(|other| other.consumes.iter())
+            .filter(|consumed| {
+                consumed.declaration_index < field.declaration_index
+                    && previous
+                        .into_iter()
+                        .all(|previous| consumed.declaration_index > previous)
+            })
+            .collect();
+        consumed.sort_by_key(|consumed| consumed.declaration_index);
+        consumed
+    }
+
+    /// The name of the struct holding all borrowed fields of a `single_allocation` struct.
+    pub fn block_ident(&self) -> Ident {
+        format_ident!("{}Block", self.ident)
+    }
+
+    /// Returns code which allocates the block of a `single_allocation` struct, to be filled in
+    /// through `block_slot.as_ptr()`.
+    pub fn make_block_slot(&self) -> TokenStream {
+        if !self.single_allocation {
+            return quote! {};
+        }
+        let block_ident = self.block_ident();
+        let generic_args = self.generic_arguments();
+        quote! {
+            let block_slot = ::ouroboros::macro_help::UninitBox::<#block_ident<#(#generic_args),*>>::new();
+        }
+    }
+
+    /// True if constructors store `field` in place, through a pointer into the memory of a pinned
+    /// struct or into the block of a `single_allocation` struct.
+    pub fn is_stored_in_place(&self, field: &StructFieldInfo) -> bool {
+        self.pinned || field.storage == FieldStorage::Block
+    }
+
+    /// Returns code which creates `drop_guard`, which drops the fields stored in place so far if
+    /// construction panics or is cancelled. `memory` points to the struct or block they are stored
+    /// in and `stored` lists them in the order constructors store them.
+    pub fn make_drop_guard(
+        &self,
+        memory: &TokenStream,
+        stored: &[&StructFieldInfo],
+    ) -> TokenStream {
+        let internal_ident = &self.internal_ident;
+        let block_ident = self.block_ident();
+        let generic_args = self.generic_arguments();
+        let memory_type = if self.pinned {
+            quote! { #internal_ident<#(#generic_args),*> }
+        } else if self.single_allocation {
+            quote! { #block_ident<#(#generic_args),*> }
+        } else {
+            return quote! {};
+        };
+        let drops = stored.iter().enumerate().rev().map(|(index, field)| {
+            let field_name = &field.name;
+            quote! {
+                if stored > #index {
+                    ::core::ptr::drop_in_place(::core::ptr::addr_of_mut!((*this).#field_name));
+                }
+            }
+        });
+        quote! {
+            let drop_stored = |this: *mut #memory_type, stored: usize| unsafe { #(#drops)* };
+            let mut drop_guard = unsafe {
+                ::ouroboros::macro_help::DropGuard::new(#memory, drop_stored)
+            };
+        }
+    }
+
+    /// For structs with an allocator, returns the `allocator` parameter of constructors, the
+    /// matching field of builder structs and how to access that field. It comes after all other
+    /// arguments, like in `Box::new_in()`.
+    pub fn allocator_arg(&self) -> Option<(TokenStream, TokenStream, TokenStream)> {
+        let allocator = self.allocator.as_ref()?;
+        if self.is_tuple() {
+            let index = syn::Index::from(self.fields.len());
+            Some((
+                quote! { allocator: #allocator },
+                quote! { #allocator },
+                quote! { #index },
+            ))
+        } else {
+            Some((
+                quote! { allocator: #allocator },
+                quote! { allocator: #allocator },
+                quote! { allocator },
+            ))
+        }
+    }
+
+    /// The type async builders return for a field, a pinned box containing a future which outputs
+    /// `output`. The box uses the allocator of the struct if it has one.
+    pub fn boxed_future_type(&self, output: TokenStream, send: bool) -> TokenStream {
+        let send = if send {
+            quote! { + ::core::marker::Send }
+        } else {
+            quote! {}
+        };
+        let future = quote! { dyn ::core::future::Future<Output=#output> #send + 'this };
+        if let Some(allocator) = &self.allocator {
+            quote! {
+                ::core::pin::Pin<::ouroboros::macro_help::allocator_api2::boxed::Box<#future, #allocator>>
+            }
+        } else {
+            quote! { ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<#future>> }
+        }
+    }
+
+    /// True if builders of `builder_type` are `AsyncFnOnce` closures. There is no way to require
+    /// the futures of an `AsyncFnOnce` to be `Send`, so the Send variants always use boxed futures.
+    pub fn uses_async_fn(&self, builder_type: BuilderType) -> bool {
+        self.async_fn && matches!(builder_type, BuilderType::Async)
+    }
+
+    /// The receiver of methods which provide mutable access to fields. Pinned structs cannot be
+    /// accessed through `&mut self`.
+    pub fn mut_self_param(&self) -> TokenStream {
+        if self.pinned {
+            quote! { self: ::core::pin::Pin<&'outer_borrow mut Self> }
+        } else {
+            quote! { &'outer_borrow mut self }
+        }
+    }
+
+    /// Code which turns a receiver created by `mut_self_param()` into a mutable reference to the
+    /// internal struct.
+    pub fn internal_mut(&self) -> TokenStream {
+        if self.pinned {
+            quote! {
+                unsafe { ::core::pin::Pin::get_unchecked_mut(self).actual_data.assume_init_mut() }
+            }
+        } else {
+            quote! { unsafe { self.actual_data.assume_init_mut() } }
+        }
+    }
+
+    /// The type returned by constructors, which is boxed and pinned for pinned structs.
+    pub fn constructed_type(&self) -> TokenStream {
+        let ident = &self.ident;
+        let generic_args = self.generic_arguments();
+        if self.pinned {
+            quote! {
+                ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<#ident<#(#generic_args),*>>>
+            }
+        } else {
+            quote! { #ident<#(#generic_args),*> }
+        }
+    }
+
+    /// True if `Deserialize` is derived, which is implemented by deserializing `Heads`.
+    pub fn derives_deserialize(&self) -> bool {
+        self.derives
+            .iter()
+            .any(|derive| matches!(derive, Derive::Deserialize))
+    }
+
+    /// True if the original struct was a tuple struct. Its builders are then tuple structs too.
+    pub fn is_tuple(&self) -> bool {
+        self.fields.iter().any(|field| field.tuple_index.is_some())
+    }
 }
 
 #[derive(Clone)]
 pub struct StructFieldInfo {
     pub name: Ident,
+    /// The position of this field if it belongs to a tuple struct. `name` is then a made up
+    /// identifier like `_0`.
+    pub tuple_index: Option<usize>,
     pub typ: Type,
     pub field_type: FieldType,
     pub vis: Visibility,
@@ -144,6 +412,36 @@ pub struct StructFieldInfo {
     /// Some(false), we should avoid making borrow_* or borrow_*_mut functions as they will not
     /// be able to compile.
     pub covariant: Option<bool>,
+    /// A function specified with `#[clone_with()]` which is used instead of `RebaseClone` to
+    /// recreate this field when deriving `Clone`.
+    pub clone_with: Option<Path>,
+    /// A function specified with `#[default_with()]` which is used instead of `Default` or
+    /// `DefaultFrom` to create this field when deriving `Default`.
+    pub default_with: Option<Path>,
+    /// Set by `#[ouroboros(skip)]`, excludes the field from all derived traits which only inspect
+    /// the existing value of the field.
+    pub skip_derives: bool,
+    pub debug_format: DebugFormat,
+    /// Set by `#[ouroboros(serialize)]`, includes a self-referencing field in the output of a
+    /// derived `Serialize` implementation. Head fields are always included.
+    pub serialize: bool,
+    /// A function specified with `#[deserialize_with()]` which is used as the builder for this
+    /// field when deriving `Deserialize`.
+    pub deserialize_with: Option<Path>,
+    pub storage: FieldStorage,
+    /// Whether `into_parts()` moves this field out of the struct instead of dropping it. This is
+    /// the case for head fields and for self-referencing fields which do not use the `'this`
+    /// lifetime if they are tail fields or are annotated with `#[extractable]`.
+    pub extractable: bool,
+    /// Fields named in `#[consumes()]`. They are not stored in the struct, their values are passed
+    /// to the constructors or built by their own builders, and moved into the builder of this field
+    /// instead.
+    pub consumes: Vec<StructFieldInfo>,
+    /// The position of this field among all fields of the struct as declared, including the ones
+    /// which are consumed by other fields. Constructors take their arguments in this order.
+    pub declaration_index: usize,
+    /// Set by `#[iterator]`. The struct implements `Iterator` by advancing this field.
+    pub iterator: bool,
 }
 
 #[derive(Clone)]
@@ -156,14 +454,90 @@ pub enum ArgType {
 }
 
 impl StructFieldInfo {
+    /// The name used to refer to this field in generated method names like `borrow_{}`. This is
+    /// the index of the field for tuple structs.
+    pub fn accessor_name(&self) -> String {
+        match self.tuple_index {
+            Some(index) => index.to_string(),
+            None => self.name.unraw().to_string(),
+        }
+    }
+
     pub fn builder_name(&self) -> Ident {
         format_ident!("{}_builder", self.name)
     }
 
+    /// How the builder structs refer to the value or builder for this field. Builders of tuple
+    /// structs are tuple structs themselves.
+    pub fn builder_member(&self) -> TokenStream {
+        match self.tuple_index {
+            Some(index) => {
+                let index = syn::Index::from(index);
+                quote! { #index }
+            }
+            None if self.borrows.is_empty() => {
+                let name = &self.name;
+                quote! { #name }
+            }
+            None => {
+                let name = self.builder_name();
+                quote! { #name }
+            }
+        }
+    }
+
+    /// The name of the generic parameter used for this field's builder in builder structs.
+    pub fn builder_generic_name(&self) -> Ident {
+        match self.tuple_index {
+            Some(index) => format_ident!("Field{}Builder_", index),
+            None => format_ident!("{}Builder_", to_class_case(&self.accessor_name())),
+        }
+    }
+
     pub fn illegal_ref_name(&self) -> Ident {
         format_ident!("{}_illegal_static_reference", self.name)
     }
 
+    /// The fields this field borrows, including the ones borrowed by the builders of the fields it
+    /// consumes, since the consumed values can be stored in this field.
+    pub fn all_borrows(&self) -> impl Iterator<Item = &BorrowRequest> {
+        self.borrows.iter().chain(
+            self.consumes
+                .iter()
+                .flat_map(|consumed| consumed.borrows.iter()),
+        )
+    }
+
+    /// Returns the arguments the constructors call the builder of this field with: the illegal
+    /// static references to the fields it borrows followed by the values it consumes.
+    pub fn builder_args(&self, info: &StructInfo) -> Vec<TokenStream> {
+        let borrowed = self
+            .borrows
+            .iter()
+            .map(|borrow| info.fields[borrow.index].illegal_ref_name());
+        let consumed = self.consumes.iter().map(|consumed| &consumed.name);
+        borrowed
+            .map(|name| quote! { #name })
+            .chain(consumed.map(|name| quote! { #name }))
+            .collect()
+    }
+
+    /// Describes the parameters of the builder of this field in generated documentation.
+    pub fn builder_doc_params(&self, info: &StructInfo) -> String {
+        let borrowed = self.borrows.iter().map(|borrow| {
+            format!(
+                "{}: &{}_",
+                info.fields[borrow.index].accessor_name(),
+                if borrow.mutable { "mut " } else { "" },
+            )
+        });
+        let consumed = self
+            .consumes
+            .iter()
+            .map(|consumed| format!("{}: _", consumed.accessor_name()));
+        borrowed.chain(consumed).collect::<Vec<_>>().join(", ")
+    }
+
     pub fn is_borrowed(&self) -> bool {
         self.field_type != FieldType::Tail
     }
@@ -172,17 +546,218 @@ impl StructFieldInfo {
         self.field_type == FieldType::BorrowedMut
     }
 
+    /// Returns code which boxes a variable with the same name as this field. Fields boxed with a
+    /// custom allocator use a clone of the `allocator` argument of the constructor.
     pub fn boxed(&self) -> TokenStream {
         let name = &self.name;
-        quote! { ::ouroboros::macro_help::aliasable_boxed(#name) }
+        match self.storage {
+            FieldStorage::StableDeref(_) => {
+                quote! { ::ouroboros::macro_help::StableDeref::into_aliasable(#name) }
+            }
+            FieldStorage::BoxedIn => quote! {
+                ::ouroboros::macro_help::aliasable_boxed_in(
+                    #name,
+                    ::core::clone::Clone::clone(&allocator),
+                )
+            },
+            _ => quote! { ::ouroboros::macro_help::aliasable_boxed(#name) },
+        }
+    }
+
+    /// The type of the references to this field given to the fields borrowing it and to the
+    /// accessors, which is the contents of the container for `#[stable_deref]` fields.
+    pub fn borrowed_type(&self) -> TokenStream {
+        let t = &self.typ;
+        if !matches!(self.storage, FieldStorage::StableDeref(_)) {
+            return quote! { #t };
+        }
+        match apparent_stable_deref_type(t) {
+            Some((StableDerefType::String, _)) => quote! { str },
+            Some((StableDerefType::Vec, Some(element_type))) => quote! { [#element_type] },
+            Some((_, Some(element_type))) => quote! { #element_type },
+            _ => unreachable!("#[stable_deref] fields are checked when parsing"),
+        }
     }
 
-    pub fn stored_type(&self) -> TokenStream {
+    pub fn stored_type(&self, info: &StructInfo) -> TokenStream {
         let t = &self.typ;
-        if self.is_borrowed() {
-            quote! { ::ouroboros::macro_help::AliasableBox<#t> }
+        match (self.field_type, self.storage) {
+            (FieldType::Tail, _) => quote! { #t },
+            (_, FieldStorage::BoxedIn) => {
+                let allocator = &info.allocator;
+                quote! { ::ouroboros::macro_help::AliasableBoxIn<#t, #allocator> }
+            }
+            (_, FieldStorage::StableDeref(StableDerefType::Arc | StableDerefType::Rc)) => {
+                quote! { #t }
+            }
+            (_, FieldStorage::StableDeref(StableDerefType::String)) => {
+                quote! { ::ouroboros::macro_help::AliasableString }
+            }
+            (_, FieldStorage::StableDeref(kind)) => {
+                let element_type = apparent_stable_deref_type(t).and_then(|(_, element)| element);
+                if kind == StableDerefType::Vec {
+                    quote! { ::ouroboros::macro_help::AliasableVec<#element_type> }
+                } else {
+                    quote! { ::ouroboros::macro_help::AliasableBox<#element_type> }
+                }
+            }
+            (_, FieldStorage::Boxed) => quote! { ::ouroboros::macro_help::AliasableBox<#t> },
+            (FieldType::Borrowed, FieldStorage::Inline | FieldStorage::Block) => quote! { #t },
+            // Shared references to the struct must not assert that this field is immutable, as it
+            // is mutated through the reference held by the field borrowing it.
+            (FieldType::BorrowedMut, FieldStorage::Inline | FieldStorage::Block) => {
+                quote! { ::core::cell::UnsafeCell<#t> }
+            }
+        }
+    }
+
+    /// Returns code which turns a reference to the internal struct into a reference to the value
+    /// of this immutably borrowed field.
+    pub fn borrow_stored(&self, this: TokenStream) -> TokenStream {
+        let field_name = &self.name;
+        match self.storage {
+            FieldStorage::Boxed | FieldStorage::BoxedIn | FieldStorage::StableDeref(_) => {
+                quote! { &*#this.#field_name }
+            }
+            FieldStorage::Inline => quote! { &#this.#field_name },
+            FieldStorage::Block => quote! { &#this._block.#field_name },
+        }
+    }
+
+    /// Like `borrow_stored`, but for mutably borrowed fields. The returned code must be used in an
+    /// unsafe block, and the caller must ensure nothing else is borrowing the field.
+    pub fn borrow_stored_mut(&self, this: TokenStream) -> TokenStream {
+        let field_name = &self.name;
+        match self.storage {
+            FieldStorage::Boxed | FieldStorage::BoxedIn | FieldStorage::StableDeref(_) => {
+                quote! { &mut *#this.#field_name }
+            }
+            FieldStorage::Inline => quote! { &mut *#this.#field_name.get() },
+            FieldStorage::Block => quote! { &mut *(*#this._block.as_ptr()).#field_name.get() },
+        }
+    }
+
+    /// Returns a pointer to the value of this field inside the internal struct `this` points to. It
+    /// points to the container itself for `#[stable_deref]` fields, whose contents move along with
+    /// the value.
+    fn value_ptr(&self, this: &TokenStream) -> TokenStream {
+        let field_name = &self.name;
+        match (self.field_type, self.storage) {
+            (FieldType::Tail, _)
+            | (_, FieldStorage::StableDeref(_))
+            | (FieldType::Borrowed, FieldStorage::Inline) => {
+                quote! { ::core::ptr::addr_of_mut!((*#this).#field_name) }
+            }
+            (_, FieldStorage::Boxed | FieldStorage::BoxedIn) => {
+                quote! { &mut **(&mut (*#this).#field_name) }
+            }
+            (FieldType::BorrowedMut, FieldStorage::Inline) => quote! {
+                ::core::cell::UnsafeCell::raw_get(::core::ptr::addr_of!((*#this).#field_name))
+            },
+            (FieldType::Borrowed, FieldStorage::Block) => quote! {
+                ::core::ptr::addr_of_mut!((*(*#this)._block.as_ptr()).#field_name)
+            },
+            (FieldType::BorrowedMut, FieldStorage::Block) => quote! {
+                ::core::cell::UnsafeCell::raw_get(
+                    ::core::ptr::addr_of!((*(*#this)._block.as_ptr()).#field_name)
+                )
+            },
+        }
+    }
+
+    /// Returns code which drops the value of this field in place, leaving the memory it is stored
+    /// in allocated. Must be used in an unsafe block.
+    pub fn drop_value(&self, this: &TokenStream) -> TokenStream {
+        let value_ptr = self.value_ptr(this);
+        quote! { ::core::ptr::drop_in_place(#value_ptr); }
+    }
+
+    /// Returns an expression which moves the value of this field out of the memory it is stored in,
+    /// which must then be overwritten with `write_value`. Must be used in an unsafe block.
+    pub fn read_value(&self, this: &TokenStream) -> TokenStream {
+        let value_ptr = self.value_ptr(this);
+        if let FieldStorage::StableDeref(_) = self.storage {
+            quote! {
+                ::ouroboros::macro_help::StableDeref::from_aliasable(::core::ptr::read(#value_ptr))
+            }
+        } else {
+            quote! { ::core::ptr::read(#value_ptr) }
+        }
+    }
+
+    /// Returns code which moves a variable with the same name as this field into the memory the
+    /// value of this field is stored in, without dropping what was there before. Must be used in an
+    /// unsafe block.
+    pub fn write_value(&self, this: &TokenStream) -> TokenStream {
+        let field_name = &self.name;
+        let value_ptr = self.value_ptr(this);
+        if let FieldStorage::StableDeref(_) = self.storage {
+            quote! {
+                ::core::ptr::write(
+                    #value_ptr,
+                    ::ouroboros::macro_help::StableDeref::into_aliasable(#field_name),
+                );
+            }
+        } else {
+            quote! { ::core::ptr::write(#value_ptr, #field_name); }
+        }
+    }
+
+    /// Returns code which turns an expression of the stored type of this field back into a value
+    /// of the type the field was declared with.
+    pub fn unstore(&self, stored: TokenStream) -> TokenStream {
+        match (self.field_type, self.storage) {
+            (FieldType::Tail, _)
+            | (FieldType::Borrowed, FieldStorage::Inline | FieldStorage::Block) => stored,
+            (_, FieldStorage::Boxed) => quote! { ::ouroboros::macro_help::unbox(#stored) },
+            (_, FieldStorage::BoxedIn) => quote! { ::ouroboros::macro_help::unbox_in(#stored) },
+            (FieldType::BorrowedMut, FieldStorage::Inline | FieldStorage::Block) => {
+                quote! { ::core::cell::UnsafeCell::into_inner(#stored) }
+            }
+            (_, FieldStorage::StableDeref(_)) => {
+                quote! { ::ouroboros::macro_help::StableDeref::from_aliasable(#stored) }
+            }
+        }
+    }
+
+    /// Returns code which moves a variable with the same name as this field into its place in the
+    /// partially initialized internal struct or block pointed to by `this`. If the field is borrowed, a
+    /// static reference to it is created as well, like `make_illegal_static_reference` does.
+    pub fn store_in_place(&self, this: &TokenStream) -> TokenStream {
+        let field_name = &self.name;
+        let ref_name = self.illegal_ref_name();
+        match self.field_type {
+            FieldType::Tail => quote! {
+                unsafe { ::core::ptr::addr_of_mut!((*#this).#field_name).write(#field_name) };
+            },
+            FieldType::Borrowed => quote! {
+                unsafe { ::core::ptr::addr_of_mut!((*#this).#field_name).write(#field_name) };
+                let #ref_name = unsafe { &*::core::ptr::addr_of!((*#this).#field_name) };
+            },
+            FieldType::BorrowedMut => quote! {
+                unsafe {
+                    ::core::ptr::addr_of_mut!((*#this).#field_name)
+                        .write(::core::cell::UnsafeCell::new(#field_name))
+                };
+                let #ref_name = unsafe {
+                    &mut *::core::cell::UnsafeCell::raw_get(::core::ptr::addr_of!((*#this).#field_name))
+                };
+            },
+        }
+    }
+
+    /// Returns code which undoes `store_in_place` for this field, either by moving it into a
+    /// variable with the same name as the field if `keep` is true or by dropping it otherwise.
+    pub fn take_from_place(&self, this: &TokenStream, keep: bool) -> TokenStream {
+        let field_name = &self.name;
+        if !keep {
+            quote! {
+                unsafe { ::core::ptr::drop_in_place(::core::ptr::addr_of_mut!((*#this).#field_name)) };
+            }
         } else {
-            quote! { #t }
+            quote! {
+                let #field_name = unsafe { ::core::ptr::read(::core::ptr::addr_of!((*#this).#field_name)) };
+            }
         }
     }
 
@@ -229,6 +804,7 @@ impl StructFieldInfo {
     pub fn make_constructor_arg_type_impl(
         &self,
         info: &StructInfo,
+        async_fn: bool,
         make_builder_return_type: impl FnOnce() -> TokenStream,
     ) -> Result<ArgType, Error> {
         let field_type = &self.typ;
@@ -244,20 +820,30 @@ impl StructFieldInfo {
             for borrow in &self.borrows {
                 if borrow.mutable {
                     let field = &info.fields[borrow.index];
-                    let field_type = &field.typ;
+                    let field_type = field.borrowed_type();
                     field_builder_params.push(quote! {
                         &'this mut #field_type
                     });
                 } else {
                     let field = &info.fields[borrow.index];
-                    let field_type = &field.typ;
+                    let field_type = field.borrowed_type();
                     field_builder_params.push(quote! {
                         &'this #field_type
                     });
                 }
             }
+            for consumed in &self.consumes {
+                let consumed_type = &consumed.typ;
+                field_builder_params.push(quote! { #consumed_type });
+            }
             let return_type = make_builder_return_type();
-            let bound = quote! { for<'this> ::core::ops::FnOnce(#(#field_builder_params),*) -> #return_type };
+            let builder_trait = if async_fn {
+                quote! { ::core::ops::AsyncFnOnce }
+            } else {
+                quote! { ::core::ops::FnOnce }
+            };
+            let bound =
+                quote! { for<'this> #builder_trait(#(#field_builder_params),*) -> #return_type };
             Ok(ArgType::TraitBound(bound))
         }
     }
@@ -270,20 +856,15 @@ impl StructFieldInfo {
         builder_type: BuilderType,
     ) -> Result<ArgType, Error> {
         let field_type = &self.typ;
+        let async_fn = info.uses_async_fn(builder_type);
         let return_ty_constructor = || match builder_type {
-            BuilderType::AsyncSend => {
-                quote! {
-                    ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<
-                        dyn ::core::future::Future<Output=#field_type> + ::core::marker::Send + 'this>>
-                }
-            }
-            BuilderType::Async => {
-                quote! { ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<
-                dyn ::core::future::Future<Output=#field_type> + 'this>> }
+            BuilderType::AsyncSend => info.boxed_future_type(quote! { #field_type }, true),
+            BuilderType::Async if !async_fn => {
+                info.boxed_future_type(quote! { #field_type }, false)
             }
-            BuilderType::Sync => quote! { #field_type },
+            BuilderType::Async | BuilderType::Sync => quote! { #field_type },
         };
-        self.make_constructor_arg_type_impl(info, return_ty_constructor)
+        self.make_constructor_arg_type_impl(info, async_fn, return_ty_constructor)
     }
 
     /// Like make_constructor_arg_type, but used for the try_new constructor.
@@ -293,23 +874,13 @@ impl StructFieldInfo {
         builder_type: BuilderType,
     ) -> Result<ArgType, Error> {
         let field_type = &self.typ;
+        let output = quote! { ::core::result::Result<#field_type, Error_> };
+        let async_fn = info.uses_async_fn(builder_type);
         let return_ty_constructor = || match builder_type {
-            BuilderType::AsyncSend => {
-                quote! {
-                    ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<
-                        dyn ::core::future::Future<Output=::core::result::Result<#field_type, Error_>>
-                            + ::core::marker::Send + 'this>>
-                }
-            }
-            BuilderType::Async => {
-                quote! {
-                    ::core::pin::Pin<::ouroboros::macro_help::alloc::boxed::Box<
-                        dyn ::core::future::Future<Output=::core::result::Result<#field_type, Error_>>
-                            + 'this>>
-                }
-            }
-            BuilderType::Sync => quote! { ::core::result::Result<#field_type, Error_> },
+            BuilderType::AsyncSend => info.boxed_future_type(output, true),
+            BuilderType::Async if !async_fn => info.boxed_future_type(output, false),
+            BuilderType::Async | BuilderType::Sync => output,
         };
-        self.make_constructor_arg_type_impl(info, return_ty_constructor)
+        self.make_constructor_arg_type_impl(info, async_fn, return_ty_constructor)
     }
 }
@@ -1,6 +1,7 @@
 extern crate proc_macro;
 
 mod covariance_detection;
+mod enums;
 mod generate;
 mod info_structures;
 mod parse;
@@ -8,17 +9,27 @@ mod utils;
 
 use crate::{
     generate::{
-        constructor::create_builder_and_constructor, derives::create_derives,
-        into_heads::make_into_heads, struc::create_internal_struct_def,
+        constructor::create_builder_and_constructor,
+        derives::create_derives,
+        into_heads::{make_into_heads, make_into_parts},
+        iterator::make_iterator_impl,
+        map::{make_map_functions, make_map_target_impl},
+        rebuild::{make_rebuild_functions, make_try_rebuild_functions},
+        reclaim::make_reclaim_functions,
+        struc::create_internal_struct_def,
         summon_checker::generate_checker_summoner,
-        try_constructor::create_try_builder_and_constructor, type_asserts::make_type_asserts,
-        with::make_with_all_function, with_each::make_with_functions,
+        try_constructor::create_try_builder_and_constructor,
+        type_asserts::{make_auto_trait_asserts, make_type_asserts},
+        with::make_with_all_function,
+        with_each::make_with_functions,
     },
     info_structures::Options,
     parse::parse_struct,
 };
 use generate::{
-    drop::create_drop_impl, struc::create_actual_struct_def, with_mut::make_with_all_mut_function,
+    drop::create_drop_impl,
+    struc::{create_actual_struct_def, create_block_struct_def, create_sync_impl},
+    with_mut::make_with_all_mut_function,
 };
 use heck::ToSnakeCase;
 use info_structures::BuilderType;
@@ -26,21 +37,23 @@ use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use proc_macro2::TokenTree;
 use quote::{format_ident, quote};
-use syn::{Error, ItemStruct};
+use syn::{Error, Item, ItemStruct};
 
 fn self_referencing_impl(
     original_struct_def: &ItemStruct,
-    options: Options,
+    options: &Options,
 ) -> Result<TokenStream, Error> {
     let struct_name = &original_struct_def.ident;
     let mod_name = format_ident!("ouroboros_impl_{}", struct_name.to_string().to_snake_case());
     let visibility = &original_struct_def.vis;
 
-    let info = parse_struct(original_struct_def)?;
+    let info = parse_struct(original_struct_def, options)?;
 
     let actual_struct_def = create_actual_struct_def(&info)?;
     let internal_struct_def = create_internal_struct_def(&info)?;
+    let block_struct_def = create_block_struct_def(&info)?;
     let drop_impl = create_drop_impl(&info)?;
+    let sync_impl = create_sync_impl(&info);
 
     let borrowchk_summoner = generate_checker_summoner(&info)?;
 
@@ -69,12 +82,20 @@ fn self_referencing_impl(
     let (with_all_mut_struct_def, with_all_mut_fn_def) =
         make_with_all_mut_function(&info, options)?;
     let (heads_struct_def, into_heads_fn) = make_into_heads(&info, options);
+    let (parts_struct_def, into_parts_fn) = make_into_parts(&info, options);
+    let reclaim_fns = make_reclaim_functions(&info, options)?;
+    let rebuild_fns = make_rebuild_functions(&info, options)?;
+    let try_rebuild_fns = make_try_rebuild_functions(&info, options)?;
+    let map_fns = make_map_functions(&info, options)?;
+    let map_target_impl = make_map_target_impl(&info)?;
+    let iterator_impl = make_iterator_impl(&info);
 
     let impls = create_derives(&info)?;
 
     // These check that types like Box, Arc, and Rc refer to those types in the std lib and have not
     // been overridden.
     let type_asserts_def = make_type_asserts(&info);
+    let auto_trait_asserts = make_auto_trait_asserts(&info, options);
 
     let extra_visibility = if options.do_pub_extras {
         visibility.clone()
@@ -92,7 +113,9 @@ fn self_referencing_impl(
             #[doc="The self-referencing struct."]
             #actual_struct_def
             #internal_struct_def
+            #block_struct_def
             #drop_impl
+            #sync_impl
             #[allow(clippy::too_many_arguments)] //This one makes a difference, verified
             #borrowchk_summoner
             #builder_def
@@ -105,7 +128,10 @@ fn self_referencing_impl(
             #with_all_mut_struct_def
             #(#with_errors)*
             #heads_struct_def
+            #parts_struct_def
             #impls
+            #map_target_impl
+            #iterator_impl
             #[allow(clippy::too_many_arguments)] //This one makes a difference, verified
             impl <#generic_params> #struct_name <#(#generic_args),*> #generic_where {
                 #constructor_def
@@ -118,8 +144,14 @@ fn self_referencing_impl(
                 #with_all_fn_def
                 #with_all_mut_fn_def
                 #into_heads_fn
+                #into_parts_fn
+                #(#reclaim_fns)*
+                #(#rebuild_fns)*
+                #(#try_rebuild_fns)*
+                #(#map_fns)*
             }
             #type_asserts_def
+            #auto_trait_asserts
         }
         #visibility use #mod_name :: #struct_name;
         #extra_visibility use #mod_name :: #builder_struct_name;
@@ -131,14 +163,53 @@ fn self_referencing_impl(
     }))
 }
 
+/// Parses the `= Type` following `allocator` in the arguments of `#[self_referencing]`, along with
+/// the comma ending it if there is one.
+fn parse_allocator_option(
+    name: &proc_macro2::Ident,
+    tokens: &mut impl Iterator<Item = TokenTree>,
+) -> Result<syn::Type, Error> {
+    match tokens.next() {
+        Some(TokenTree::Punct(punct)) if punct.as_char() == '=' => (),
+        _ => return Err(Error::new_spanned(name, "Expected 'allocator = Type'.")),
+    }
+    let mut type_tokens = TokenStream2::new();
+    // Commas inside generic arguments do not end the type. The '>' of '->' does not close one.
+    let mut depth = 0;
+    let mut after_dash = false;
+    for token in tokens {
+        let mut dash = false;
+        if let TokenTree::Punct(punct) = &token {
+            match punct.as_char() {
+                ',' if depth == 0 => break,
+                '<' => depth += 1,
+                '>' if !after_dash => depth -= 1,
+                '-' => dash = punct.spacing() == proc_macro2::Spacing::Joint,
+                _ => (),
+            }
+        }
+        after_dash = dash;
+        type_tokens.extend(std::iter::once(token));
+    }
+    syn::parse2(type_tokens)
+}
+
 #[proc_macro_attribute]
 pub fn self_referencing(attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut options = Options {
         do_no_doc: false,
         do_pub_extras: false,
+        do_pinned: false,
+        do_single_allocation: false,
+        do_stable_deref: false,
+        allocator: None,
+        do_assert_send: false,
+        do_assert_sync: false,
+        do_async_fn: false,
     };
     let mut expecting_comma = false;
-    for token in <TokenStream as std::convert::Into<TokenStream2>>::into(attr).into_iter() {
+    let mut tokens = <TokenStream as std::convert::Into<TokenStream2>>::into(attr).into_iter();
+    while let Some(token) = tokens.next() {
         if let TokenTree::Ident(ident) = &token {
             if expecting_comma {
                 return Error::new(token.span(), "Unexpected identifier, expected comma.")
@@ -148,10 +219,28 @@ pub fn self_referencing(attr: TokenStream, item: TokenStream) -> TokenStream {
             match &ident.to_string()[..] {
                 "no_doc" => options.do_no_doc = true,
                 "pub_extras" => options.do_pub_extras = true,
+                "pinned" => options.do_pinned = true,
+                "single_allocation" => options.do_single_allocation = true,
+                "stable_deref" => options.do_stable_deref = true,
+                "assert_send" => options.do_assert_send = true,
+                "assert_sync" => options.do_assert_sync = true,
+                "async_fn" => options.do_async_fn = true,
+                "allocator" => {
+                    match parse_allocator_option(ident, &mut tokens) {
+                        Ok(allocator) => options.allocator = Some(allocator),
+                        Err(err) => return err.to_compile_error().into(),
+                    }
+                    // The comma after the type has already been consumed.
+                    continue;
+                }
                 _ => {
                     return Error::new_spanned(
                         ident,
-                        "Unknown identifier, expected 'no_doc' or 'pub_extras'.",
+                        concat!(
+                            "Unknown identifier, expected 'no_doc', 'pub_extras', 'pinned', ",
+                            "'single_allocation', 'stable_deref', 'allocator', 'assert_send', ",
+                            "'assert_sync' or 'async_fn'."
+                        ),
                     )
                     .to_compile_error()
                     .into()
@@ -176,8 +265,18 @@ pub fn self_referencing(attr: TokenStream, item: TokenStream) -> TokenStream {
                 .into();
         }
     }
-    let original_struct_def: ItemStruct = syn::parse_macro_input!(item);
-    match self_referencing_impl(&original_struct_def, options) {
+    let original_def: Item = syn::parse_macro_input!(item);
+    let result = match &original_def {
+        Item::Struct(original_struct_def) => self_referencing_impl(original_struct_def, &options),
+        Item::Enum(original_enum_def) => {
+            enums::self_referencing_enum_impl(original_enum_def, &options).map(TokenStream::from)
+        }
+        _ => Err(Error::new_spanned(
+            &original_def,
+            "#[self_referencing] can only be applied to structs and enums.",
+        )),
+    };
+    match result {
         Ok(content) => content,
         Err(err) => err.to_compile_error().into(),
     }
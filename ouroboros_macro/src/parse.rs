@@ -1,13 +1,20 @@
-use proc_macro2::{Span, TokenTree};
-use quote::format_ident;
+use proc_macro2::{Ident, Span, TokenTree};
+use quote::{format_ident, ToTokens};
 use syn::{
-    spanned::Spanned, Attribute, Error, Fields, GenericParam, ItemStruct, MacroDelimiter, Meta,
+    ext::IdentExt, spanned::Spanned, Attribute, Error, Field, Fields, GenericParam, ItemStruct,
+    MacroDelimiter, Meta,
 };
 
 use crate::{
-    covariance_detection::type_is_covariant_over_this_lifetime,
-    info_structures::{BorrowRequest, Derive, FieldType, StructFieldInfo, StructInfo},
-    utils::submodule_contents_visibility,
+    covariance_detection::{
+        apparent_stable_deref_type, apparent_std_borrowing_iterator_type,
+        type_is_covariant_over_this_lifetime,
+    },
+    info_structures::{
+        BorrowRequest, DebugFormat, Derive, FieldStorage, FieldType, Options, StructFieldInfo,
+        StructInfo,
+    },
+    utils::{submodule_contents_visibility, uses_this_lifetime},
 };
 
 fn handle_borrows_attr(
@@ -27,85 +34,183 @@ fn handle_borrows_attr(
         }
     };
     for token in tokens {
-        if let TokenTree::Ident(ident) = token {
-            if waiting_for_comma {
-                return Err(Error::new_spanned(&ident, "Expected comma."));
-            }
-            let istr = ident.to_string();
-            if istr == "mut" {
+        let borrowed_name = match &token {
+            TokenTree::Ident(ident) if ident == "mut" => {
+                if waiting_for_comma {
+                    return Err(Error::new_spanned(ident, "Expected comma."));
+                }
                 if borrow_mut {
-                    return Err(Error::new_spanned(&ident, "Unexpected double 'mut'"));
+                    return Err(Error::new_spanned(ident, "Unexpected double 'mut'"));
                 }
                 borrow_mut = true;
-            } else {
-                let index = field_info.iter().position(|item| item.name == istr);
-                let index = if let Some(v) = index {
-                    v
-                } else {
+                continue;
+            }
+            TokenTree::Ident(ident) => ident.unraw().to_string(),
+            // Fields of tuple structs are borrowed by their index, E.G. `#[borrows(0)]`.
+            TokenTree::Literal(literal) => literal.to_string(),
+            TokenTree::Punct(punct) => {
+                if punct.as_char() != ',' {
                     return Err(Error::new_spanned(
-                        &ident,
-                        concat!(
-                            "Unknown identifier, make sure that it is spelled ",
-                            "correctly and defined above the location it is borrowed."
-                        ),
+                        punct,
+                        "Unexpected punctuation, expected comma or identifier.",
                     ));
-                };
-                if borrow_mut {
-                    if field_info[index].field_type == FieldType::Borrowed {
-                        return Err(Error::new_spanned(
-                            &ident,
-                            "Cannot borrow mutably, this field was previously borrowed immutably.",
-                        ));
-                    }
-                    if field_info[index].field_type == FieldType::BorrowedMut {
-                        return Err(Error::new_spanned(&ident, "Cannot borrow mutably twice."));
-                    }
-                    field_info[index].field_type = FieldType::BorrowedMut;
-                } else {
-                    if field_info[index].field_type == FieldType::BorrowedMut {
-                        return Err(Error::new_spanned(
-                            &ident,
-                            "Cannot borrow as immutable as it was previously borrowed mutably.",
-                        ));
-                    }
-                    field_info[index].field_type = FieldType::Borrowed;
                 }
-                borrows.push(BorrowRequest {
-                    index,
-                    mutable: borrow_mut,
-                });
-                waiting_for_comma = true;
-                borrow_mut = false;
-            }
-        } else if let TokenTree::Punct(punct) = token {
-            if punct.as_char() == ',' {
-                if waiting_for_comma {
-                    waiting_for_comma = false;
-                } else {
-                    return Err(Error::new_spanned(&punct, "Unexpected extra comma."));
+                if !waiting_for_comma {
+                    return Err(Error::new_spanned(punct, "Unexpected extra comma."));
                 }
-            } else {
+                waiting_for_comma = false;
+                continue;
+            }
+            TokenTree::Group(..) => {
                 return Err(Error::new_spanned(
-                    &punct,
-                    "Unexpected punctuation, expected comma or identifier.",
+                    &token,
+                    "Unexpected token, expected comma or identifier.",
                 ));
             }
+        };
+        if waiting_for_comma {
+            return Err(Error::new_spanned(&token, "Expected comma."));
+        }
+        let index = field_info
+            .iter()
+            .position(|item| item.accessor_name() == borrowed_name);
+        let index = if let Some(v) = index {
+            v
         } else {
             return Err(Error::new_spanned(
                 &token,
-                "Unexpected token, expected comma or identifier.",
+                concat!(
+                    "Unknown identifier, make sure that it is spelled ",
+                    "correctly and defined above the location it is borrowed."
+                ),
             ));
+        };
+        if borrow_mut {
+            if field_info[index].field_type == FieldType::Borrowed {
+                return Err(Error::new_spanned(
+                    &token,
+                    "Cannot borrow mutably, this field was previously borrowed immutably.",
+                ));
+            }
+            if field_info[index].field_type == FieldType::BorrowedMut {
+                return Err(Error::new_spanned(&token, "Cannot borrow mutably twice."));
+            }
+            field_info[index].field_type = FieldType::BorrowedMut;
+        } else {
+            if field_info[index].field_type == FieldType::BorrowedMut {
+                return Err(Error::new_spanned(
+                    &token,
+                    "Cannot borrow as immutable as it was previously borrowed mutably.",
+                ));
+            }
+            field_info[index].field_type = FieldType::Borrowed;
         }
+        borrows.push(BorrowRequest {
+            index,
+            mutable: borrow_mut,
+        });
+        waiting_for_comma = true;
+        borrow_mut = false;
     }
     Ok(())
 }
 
+/// Removes the fields named in a `#[consumes()]` annotation from `fields` and returns them. Indexes
+/// of borrowed fields which come after them are updated, including the ones in `borrows`.
+fn handle_consumes_attr(
+    fields: &mut Vec<StructFieldInfo>,
+    attr: &Attribute,
+    borrows: &mut [BorrowRequest],
+) -> Result<Vec<StructFieldInfo>, Error> {
+    let names = attr.parse_args_with(
+        syn::punctuated::Punctuated::<syn::Member, syn::Token![,]>::parse_terminated,
+    )?;
+    let mut consumed = Vec::new();
+    for name in names {
+        let consumed_name = match &name {
+            syn::Member::Named(ident) => ident.unraw().to_string(),
+            syn::Member::Unnamed(index) => index.index.to_string(),
+        };
+        let index = fields
+            .iter()
+            .position(|field| field.accessor_name() == consumed_name);
+        let index = if let Some(index) = index {
+            index
+        } else {
+            return Err(Error::new_spanned(
+                &name,
+                concat!(
+                    "Unknown identifier, make sure that it is spelled ",
+                    "correctly and defined above the location it is consumed."
+                ),
+            ));
+        };
+        let field = &fields[index];
+        if field.is_borrowed() {
+            return Err(Error::new_spanned(
+                &name,
+                "Cannot consume a field which is borrowed by other fields.",
+            ));
+        } else if !field.consumes.is_empty() {
+            return Err(Error::new_spanned(
+                &name,
+                "Cannot consume a field which consumes other fields itself.",
+            ));
+        } else if field.borrows.is_empty() && uses_this_lifetime(field.typ.to_token_stream()) {
+            return Err(Error::new_spanned(
+                &name,
+                concat!(
+                    "Fields using the 'this lifetime can only be consumed if they are created by ",
+                    "a builder, which requires a #[borrows()] annotation."
+                ),
+            ));
+        }
+        consumed.push(fields.remove(index));
+        // The fields consumed by the same or other fields borrow by index as well.
+        let remaining_borrows = fields
+            .iter_mut()
+            .flat_map(|field| {
+                let consumed_borrows = field
+                    .consumes
+                    .iter_mut()
+                    .flat_map(|consumed| consumed.borrows.iter_mut());
+                field.borrows.iter_mut().chain(consumed_borrows)
+            })
+            .chain(
+                consumed
+                    .iter_mut()
+                    .flat_map(|field| field.borrows.iter_mut()),
+            )
+            .chain(borrows.iter_mut());
+        for borrow in remaining_borrows {
+            if borrow.index > index {
+                borrow.index -= 1;
+            }
+        }
+    }
+    Ok(consumed)
+}
+
 fn parse_derive_token(token: &TokenTree) -> Result<Option<Derive>, Error> {
     match token {
         TokenTree::Ident(ident) => match &ident.to_string()[..] {
             "Debug" => Ok(Some(Derive::Debug)),
             "PartialEq" => Ok(Some(Derive::PartialEq)),
             "Eq" => Ok(Some(Derive::Eq)),
+            "Clone" => Ok(Some(Derive::Clone)),
+            "Default" => Ok(Some(Derive::Default)),
+            "Hash" => Ok(Some(Derive::Hash)),
+            "PartialOrd" => Ok(Some(Derive::PartialOrd)),
+            "Ord" => Ok(Some(Derive::Ord)),
+            "Serialize" | "Deserialize" if !cfg!(feature = "serde") => Err(Error::new(
+                ident.span(),
+                format!(
+                    "{} can only be derived when the serde feature of ouroboros is enabled",
+                    ident
+                ),
+            )),
+            "Serialize" => Ok(Some(Derive::Serialize)),
+            "Deserialize" => Ok(Some(Derive::Deserialize)),
             _ => Err(Error::new(
                 ident.span(),
                 format!("{} cannot be derived for self-referencing structs", ident),
@@ -139,69 +244,201 @@ fn parse_derive_attribute(attr: &Attribute) -> Result<Vec<Derive>, Error> {
     Ok(derives)
 }
 
-pub fn parse_struct(def: &ItemStruct) -> Result<StructInfo, Error> {
+fn parse_field(
+    field: &Field,
+    name: Ident,
+    tuple_index: Option<usize>,
+    fields: &mut Vec<StructFieldInfo>,
+) -> Result<(), Error> {
+    let declaration_index = fields.len()
+        + fields
+            .iter()
+            .map(|field| field.consumes.len())
+            .sum::<usize>();
+    let mut borrows = Vec::new();
+    let mut self_referencing = false;
+    let mut covariant = type_is_covariant_over_this_lifetime(&field.ty);
+    let mut clone_with = None;
+    let mut default_with = None;
+    let mut skip_derives = false;
+    let mut serialize = false;
+    let mut deserialize_with = None;
+    let mut debug_format = DebugFormat::Default;
+    let mut storage = FieldStorage::Boxed;
+    let mut extractable = false;
+    let mut iterator = false;
+    let mut consumes_attrs = Vec::new();
+    for attr in field.attrs.iter() {
+        let path = &attr.path();
+        if path.leading_colon.is_some() {
+            continue;
+        }
+        if path.segments.len() != 1 {
+            continue;
+        }
+        if path.segments.first().unwrap().ident == "borrows" {
+            if self_referencing {
+                panic!("TODO: Nice error, used #[borrows()] twice.");
+            }
+            self_referencing = true;
+            handle_borrows_attr(&mut fields[..], attr, &mut borrows)?;
+        }
+        if path.segments.first().unwrap().ident == "covariant" {
+            if covariant.is_some() {
+                panic!("TODO: Nice error, covariance specified twice.");
+            }
+            covariant = Some(true);
+        }
+        if path.segments.first().unwrap().ident == "not_covariant" {
+            if covariant.is_some() {
+                panic!("TODO: Nice error, covariance specified twice.");
+            }
+            covariant = Some(false);
+        }
+        if path.segments.first().unwrap().ident == "clone_with" {
+            if clone_with.is_some() {
+                return Err(Error::new_spanned(
+                    attr,
+                    "#[clone_with()] can only be used once.",
+                ));
+            }
+            clone_with = Some(attr.parse_args()?);
+        }
+        if path.segments.first().unwrap().ident == "default_with" {
+            if default_with.is_some() {
+                return Err(Error::new_spanned(
+                    attr,
+                    "#[default_with()] can only be used once.",
+                ));
+            }
+            default_with = Some(attr.parse_args()?);
+        }
+        if path.segments.first().unwrap().ident == "deserialize_with" {
+            if deserialize_with.is_some() {
+                return Err(Error::new_spanned(
+                    attr,
+                    "#[deserialize_with()] can only be used once.",
+                ));
+            }
+            deserialize_with = Some(attr.parse_args()?);
+        }
+        if path.segments.first().unwrap().ident == "consumes" {
+            consumes_attrs.push(attr);
+        }
+        if path.segments.first().unwrap().ident == "extractable" {
+            extractable = true;
+        }
+        if path.segments.first().unwrap().ident == "iterator" {
+            if iterator {
+                return Err(Error::new_spanned(
+                    attr,
+                    "#[iterator] can only be used once.",
+                ));
+            }
+            iterator = true;
+        }
+        if path.segments.first().unwrap().ident == "stable_deref" {
+            storage = match apparent_stable_deref_type(&field.ty) {
+                Some((container, _)) => FieldStorage::StableDeref(container),
+                None => {
+                    return Err(Error::new_spanned(
+                        &field.ty,
+                        concat!(
+                            "#[stable_deref] can only be used on fields of type Box<T>, Arc<T>, ",
+                            "Rc<T>, Vec<T> or String."
+                        ),
+                    ))
+                }
+            };
+        }
+        if path.segments.first().unwrap().ident == "ouroboros" {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip_derives = true;
+                    Ok(())
+                } else if meta.path.is_ident("serialize") {
+                    serialize = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `serialize`"))
+                }
+            })?;
+        }
+        if path.segments.first().unwrap().ident == "debug" {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    debug_format = DebugFormat::Skip;
+                    Ok(())
+                } else if meta.path.is_ident("with") {
+                    debug_format = DebugFormat::With(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `with = path::to_fn`"))
+                }
+            })?;
+        }
+    }
+    // Handled last, as removing consumed fields changes the indexes of borrowed fields.
+    let mut consumes = Vec::new();
+    for attr in consumes_attrs {
+        if tuple_index.is_some() {
+            return Err(Error::new_spanned(
+                attr,
+                "#[consumes()] cannot be used in tuple structs.",
+            ));
+        } else if borrows.is_empty() {
+            return Err(Error::new_spanned(
+                attr,
+                "#[consumes()] can only be used on fields with a #[borrows()] annotation.",
+            ));
+        }
+        consumes.extend(handle_consumes_attr(fields, attr, &mut borrows)?);
+    }
+    // We should not be able to access the field outside of the hidden module where
+    // everything is generated.
+    let with_vis = submodule_contents_visibility(&field.vis.clone());
+    fields.push(StructFieldInfo {
+        name,
+        tuple_index,
+        typ: field.ty.clone(),
+        field_type: FieldType::Tail,
+        vis: with_vis,
+        borrows,
+        self_referencing,
+        covariant,
+        clone_with,
+        default_with,
+        skip_derives,
+        debug_format,
+        serialize,
+        deserialize_with,
+        storage,
+        extractable,
+        consumes,
+        declaration_index,
+        iterator,
+    });
+    Ok(())
+}
+
+pub fn parse_struct(def: &ItemStruct, options: &Options) -> Result<StructInfo, Error> {
     let vis = def.vis.clone();
     let generics = def.generics.clone();
-    let mut actual_struct_def = def.clone();
-    actual_struct_def.vis = vis.clone();
     let mut fields = Vec::new();
-    match &mut actual_struct_def.fields {
+    match &def.fields {
         Fields::Named(def_fields) => {
-            for field in &mut def_fields.named {
-                let mut borrows = Vec::new();
-                let mut self_referencing = false;
-                let mut covariant = type_is_covariant_over_this_lifetime(&field.ty);
-                let mut remove_attrs = Vec::new();
-                for (index, attr) in field.attrs.iter().enumerate() {
-                    let path = &attr.path();
-                    if path.leading_colon.is_some() {
-                        continue;
-                    }
-                    if path.segments.len() != 1 {
-                        continue;
-                    }
-                    if path.segments.first().unwrap().ident == "borrows" {
-                        if self_referencing {
-                            panic!("TODO: Nice error, used #[borrows()] twice.");
-                        }
-                        self_referencing = true;
-                        handle_borrows_attr(&mut fields[..], attr, &mut borrows)?;
-                        remove_attrs.push(index);
-                    }
-                    if path.segments.first().unwrap().ident == "covariant" {
-                        if covariant.is_some() {
-                            panic!("TODO: Nice error, covariance specified twice.");
-                        }
-                        covariant = Some(true);
-                        remove_attrs.push(index);
-                    }
-                    if path.segments.first().unwrap().ident == "not_covariant" {
-                        if covariant.is_some() {
-                            panic!("TODO: Nice error, covariance specified twice.");
-                        }
-                        covariant = Some(false);
-                        remove_attrs.push(index);
-                    }
-                }
-                // We should not be able to access the field outside of the hidden module where
-                // everything is generated.
-                let with_vis = submodule_contents_visibility(&field.vis.clone());
-                fields.push(StructFieldInfo {
-                    name: field.ident.clone().expect("Named field has no name."),
-                    typ: field.ty.clone(),
-                    field_type: FieldType::Tail,
-                    vis: with_vis,
-                    borrows,
-                    self_referencing,
-                    covariant,
-                });
+            for field in &def_fields.named {
+                let name = field.ident.clone().expect("Named field has no name.");
+                parse_field(field, name, None, &mut fields)?;
             }
         }
-        Fields::Unnamed(_fields) => {
-            return Err(Error::new(
-                Span::call_site(),
-                "Tuple structs are not supported yet.",
-            ))
+        Fields::Unnamed(def_fields) => {
+            for (index, field) in def_fields.unnamed.iter().enumerate() {
+                // Tuple fields are stored in named fields of the internal struct, so they need an
+                // identifier. Generated methods use the index instead, E.G. `borrow_0()`.
+                let name = format_ident!("_{}", index);
+                parse_field(field, name, Some(index), &mut fields)?;
+            }
         }
         Fields::Unit => {
             return Err(Error::new(
@@ -231,7 +468,7 @@ pub fn parse_struct(def: &ItemStruct) -> Result<StructInfo, Error> {
                     "Self-referencing struct cannot be made entirely of tail fields, try adding ",
                     "#[borrows({0})] to a field defined after {0}."
                 ),
-                fields[0].name
+                fields[0].accessor_name()
             ),
         ));
     }
@@ -265,6 +502,180 @@ pub fn parse_struct(def: &ItemStruct) -> Result<StructInfo, Error> {
         }
     }
 
+    if options.do_stable_deref {
+        if options.do_pinned || options.do_single_allocation {
+            return Err(Error::new(
+                Span::call_site(),
+                concat!(
+                    "stable_deref cannot be combined with pinned or single_allocation, which do ",
+                    "not box borrowed fields."
+                ),
+            ));
+        }
+        // Arc and Rc fields which are borrowed mutably keep being boxed, as their contents
+        // cannot be borrowed mutably.
+        for field in &mut fields {
+            if !field.is_borrowed() || field.storage != FieldStorage::Boxed {
+                continue;
+            }
+            if let Some((container, _)) = apparent_stable_deref_type(&field.typ) {
+                if container.is_unique() || !field.is_mutably_borrowed() {
+                    field.storage = FieldStorage::StableDeref(container);
+                }
+            }
+        }
+    }
+
+    for field in &fields {
+        let container = if let FieldStorage::StableDeref(container) = field.storage {
+            container
+        } else {
+            continue;
+        };
+        let error = if !field.is_borrowed() {
+            "#[stable_deref] can only be used on fields which are borrowed by other fields."
+        } else if field.is_mutably_borrowed() && !container.is_unique() {
+            concat!(
+                "Arc and Rc fields cannot be mutably borrowed, as they only give shared access to ",
+                "their contents."
+            )
+        } else if options.do_pinned || options.do_single_allocation {
+            concat!(
+                "#[stable_deref] cannot be used in pinned or single_allocation structs, which do ",
+                "not box borrowed fields."
+            )
+        } else {
+            continue;
+        };
+        return Err(Error::new_spanned(&field.typ, error));
+    }
+
+    for field in &mut fields {
+        let uses_this = uses_this_lifetime(field.typ.to_token_stream());
+        if field.deserialize_with.is_some() && !field.self_referencing {
+            return Err(Error::new_spanned(
+                &field.typ,
+                concat!(
+                    "#[deserialize_with()] is only needed on fields with a #[borrows()] ",
+                    "annotation, head fields are deserialized directly."
+                ),
+            ));
+        } else if field.extractable && !field.self_referencing {
+            return Err(Error::new_spanned(
+                &field.typ,
+                "#[extractable] is only needed on fields with a #[borrows()] annotation.",
+            ));
+        } else if field.extractable && uses_this {
+            return Err(Error::new_spanned(
+                &field.typ,
+                concat!(
+                    "Fields using the 'this lifetime cannot be extractable, as they would refer ",
+                    "to fields which have been dropped."
+                ),
+            ));
+        }
+        field.extractable = !field.self_referencing
+            || (!uses_this && (field.extractable || field.field_type.is_tail()));
+    }
+
+    let mut iterator_fields = fields.iter().filter(|field| field.iterator);
+    if let Some(field) = iterator_fields.next() {
+        if iterator_fields.next().is_some() {
+            return Err(Error::new_spanned(
+                &field.typ,
+                "#[iterator] can only be used on one field of a struct.",
+            ));
+        } else if field.is_borrowed() {
+            return Err(Error::new_spanned(
+                &field.typ,
+                concat!(
+                    "#[iterator] can only be used on fields which are not borrowed by other ",
+                    "fields, as advancing the iterator requires mutable access to it."
+                ),
+            ));
+        } else if options.do_pinned {
+            return Err(Error::new_spanned(
+                &field.typ,
+                concat!(
+                    "#[iterator] cannot be used in pinned structs, which cannot be mutably ",
+                    "accessed through `&mut self`."
+                ),
+            ));
+        } else if apparent_std_borrowing_iterator_type(&field.typ) {
+            return Err(Error::new_spanned(
+                &field.typ,
+                concat!(
+                    "The items of an #[iterator] field must not borrow 'this, as they would ",
+                    "outlive the fields they point to. Map them to owned values instead, E.G. ",
+                    "with `.cloned()`."
+                ),
+            ));
+        }
+    }
+
+    if options.do_pinned && options.do_single_allocation {
+        return Err(Error::new(
+            Span::call_site(),
+            concat!(
+                "pinned and single_allocation cannot be combined, pinned structs already store ",
+                "borrowed fields without allocating them."
+            ),
+        ));
+    } else if options.do_pinned {
+        for field in &mut fields {
+            field.storage = FieldStorage::Inline;
+        }
+    } else if options.do_single_allocation {
+        for field in &mut fields {
+            if field.is_borrowed() {
+                field.storage = FieldStorage::Block;
+            }
+        }
+    }
+
+    if let Some(allocator) = &options.allocator {
+        if !cfg!(feature = "allocator_api2") {
+            return Err(Error::new_spanned(
+                allocator,
+                "allocator can only be used when the allocator_api2 feature of ouroboros is enabled",
+            ));
+        } else if options.do_pinned || options.do_single_allocation {
+            return Err(Error::new_spanned(
+                allocator,
+                concat!(
+                    "allocator cannot be used in pinned or single_allocation structs, which do not ",
+                    "box borrowed fields individually."
+                ),
+            ));
+        } else if derives.iter().any(|derive| {
+            matches!(
+                derive,
+                Derive::Clone | Derive::Default | Derive::Deserialize
+            )
+        }) {
+            return Err(Error::new_spanned(
+                allocator,
+                concat!(
+                    "Clone, Default and Deserialize cannot be derived for structs with an ",
+                    "allocator, as there is no allocator to create the new instance with."
+                ),
+            ));
+        } else if fields.iter().any(|field| field.name == "allocator") {
+            return Err(Error::new_spanned(
+                allocator,
+                concat!(
+                    "Fields cannot be named 'allocator' as it is used as an argument of the ",
+                    "constructors."
+                ),
+            ));
+        }
+        for field in &mut fields {
+            if field.is_borrowed() && field.storage == FieldStorage::Boxed {
+                field.storage = FieldStorage::BoxedIn;
+            }
+        }
+    }
+
     Ok(StructInfo {
         derives,
         ident: def.ident.clone(),
@@ -274,5 +685,9 @@ pub fn parse_struct(def: &ItemStruct) -> Result<StructInfo, Error> {
         vis,
         first_lifetime,
         attributes,
+        pinned: options.do_pinned,
+        single_allocation: options.do_single_allocation,
+        allocator: options.allocator.clone(),
+        async_fn: options.do_async_fn,
     })
 }
@@ -67,16 +67,8 @@ pub fn make_generic_arguments(generics: Vec<&GenericParam>) -> Vec<TokenStream>
 pub fn uses_this_lifetime(input: TokenStream) -> bool {
     for token in input.into_iter() {
         match token {
-            TokenTree::Ident(ident) => {
-                if ident == "this" {
-                    return true;
-                }
-            }
-            TokenTree::Group(group) => {
-                if uses_this_lifetime(group.stream()) {
-                    return true;
-                }
-            }
+            TokenTree::Ident(ident) if ident == "this" => return true,
+            TokenTree::Group(group) if uses_this_lifetime(group.stream()) => return true,
             _ => (),
         }
     }